@@ -0,0 +1,236 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result, cards::{DeckReport, NoteChange, split_id}, defaults::DEFAULTS_FILE, hooks::{Hooks, PRE_PARSE, POST_PARSE}};
+
+#[derive(Serialize, Deserialize)]
+pub struct CachedFile {
+    pub modified: u64, // nanoseconds since the epoch
+    pub hash: String,
+    pub ids: Vec<String>,
+    /// Hash of the settings the file was saved with, see `Settings`
+    #[serde(default)]
+    pub settings: String,
+}
+
+/// Hashes everything besides a file's own content that decides its notes, so that changing it saves the file again
+///
+/// That's `.anc/config`, `.anc/defaults`, the `pre-parse` and `post-parse` hooks, the `_defaults.yaml` of every
+/// directory down to the file's own and its converter.
+pub struct Settings<'a> {
+    base_dir: PathBuf,
+    hooks: &'a Hooks,
+    project: String,
+    dirs: HashMap<PathBuf, String>, // of each directory read so far
+}
+
+impl<'a> Settings<'a> {
+    pub fn new(config_dir: &Path, hooks: &'a Hooks) -> Settings<'a> {
+        let hooks_dir = config_dir.join("hooks");
+        Settings {
+            base_dir: config_dir.parent().unwrap().to_path_buf(),
+            hooks,
+            project: hash_files("", &[
+                config_dir.join("config"),
+                config_dir.join("defaults"),
+                hooks_dir.join(PRE_PARSE),
+                hooks_dir.join(POST_PARSE),
+            ]),
+            dirs: HashMap::new(),
+        }
+    }
+
+    fn of_file(&mut self, path: &Path) -> String {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let settings = match dir.starts_with(&self.base_dir) {
+            true => self.of_dir(dir),
+            false => self.project.clone(),
+        };
+        match self.hooks.converter(path) {
+            Some(converter) => hash_files(&settings, &[converter.to_path_buf()]),
+            None => settings,
+        }
+    }
+
+    fn of_dir(&mut self, dir: &Path) -> String {
+        if let Some(settings) = self.dirs.get(dir) {
+            return settings.clone();
+        }
+        let parent = match dir.parent() {
+            Some(parent) if dir != self.base_dir => self.of_dir(parent),
+            _ => self.project.clone(),
+        };
+        let settings = hash_files(&parent, &[dir.join(DEFAULTS_FILE)]);
+        self.dirs.insert(dir.to_path_buf(), settings.clone());
+        settings
+    }
+}
+
+/// Files as they were at the last successful save, kept in `.anc/cache` so unchanged files can be skipped
+#[derive(Serialize, Deserialize, Default)]
+pub struct FileCache {
+    files: HashMap<String, CachedFile>,
+}
+
+impl FileCache {
+    pub fn load(config_dir: &Path) -> Result<FileCache> {
+        match fs::read_to_string(cache_path(config_dir)) {
+            Err(_) => Ok(FileCache::default()),
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| Error::Config(format!("Could not read .anc/cache: {}", e))),
+        }
+    }
+
+    pub fn save(&self, config_dir: &Path) -> Result<()> {
+        let path = cache_path(config_dir);
+        fs::write(&path, serde_json::to_vec(self).unwrap())
+            .map_err(|e| Error::io(path, e))
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&CachedFile> {
+        self.files.get(&path.display().to_string())
+    }
+
+    /// The paths whose content or settings differ from when they were last saved
+    ///
+    /// The modification time is checked first so that unchanged files don't have to be read.
+    pub fn changed(&self, paths: &[PathBuf], settings: &mut Settings) -> Vec<PathBuf> {
+        paths.iter()
+            .filter(|p| {
+                let cached = match self.get(p) {
+                    None => return true,
+                    Some(c) => c,
+                };
+                if cached.settings != settings.of_file(p) {
+                    return true;
+                }
+                match modified(p) {
+                    Some(m) if m == cached.modified => false,
+                    _ => content_hash(p).is_none_or(|h| h != cached.hash),
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets files that don't exist anymore
+    pub fn retain(&mut self, paths: &[PathBuf]) {
+        let existing: Vec<String> = paths.iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        self.files.retain(|p, _| existing.contains(p));
+    }
+
+    /// Marks `paths` as saved, along with the notes they produced
+    ///
+    /// Files with a conflicting note stay out of date so the conflict shows up again on the next save.
+    pub fn record(&mut self, paths: &[PathBuf], reports: &[DeckReport], settings: &mut Settings) {
+        let mut ids: HashMap<&str, Vec<String>> = HashMap::new();
+        let mut conflicts = vec![];
+        for (id, change) in reports.iter().flat_map(|r| r.notes.iter()) {
+            let (file, _) = split_id(id);
+            if *change == NoteChange::Conflict {
+                conflicts.push(file);
+            }
+            ids.entry(file).or_default().push(id.clone());
+        }
+
+        for p in paths {
+            let key = p.display().to_string();
+            if conflicts.contains(&key.as_str()) {
+                self.files.remove(&key);
+                continue;
+            }
+            if let (Some(modified), Some(hash)) = (modified(p), content_hash(p)) {
+                let file_ids = ids.remove(key.as_str()).unwrap_or_default();
+                self.files.insert(key, CachedFile {
+                    modified,
+                    hash,
+                    ids: file_ids,
+                    settings: settings.of_file(p),
+                });
+            }
+        }
+    }
+}
+
+fn cache_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("cache")
+}
+
+fn modified(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?
+        .modified().ok()?
+        .duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    Some(modified.as_nanos() as u64)
+}
+
+fn content_hash(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    Some(sha1::Sha1::from(content).digest().to_string())
+}
+
+/// Hash of `previous` and the content of each of `files`, where missing ones count as empty
+fn hash_files(previous: &str, files: &[PathBuf]) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(previous.as_bytes());
+    for file in files {
+        hasher.update(&[0]);
+        hasher.update(&fs::read(file).unwrap_or_default());
+    }
+    hasher.digest().to_string()
+}
+
+#[test]
+fn changed_files() {
+    use std::collections::BTreeMap;
+    use crate::hooks::{Converters, Protocol, DEFAULT_TIMEOUT};
+
+    let dir = std::env::temp_dir().join(format!("anc-cache-{}", std::process::id()));
+    let config_dir = dir.join(".anc");
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(config_dir.join("config"), "").unwrap();
+    let hooks = Hooks::new(&config_dir, Protocol::V1, DEFAULT_TIMEOUT, Converters::new(&config_dir, BTreeMap::new()).unwrap());
+    let (a, b, c) = (dir.join("a.qz"), dir.join("b.qz"), dir.join("sub/c.qz"));
+    for p in [&a, &b, &c] {
+        fs::write(p, "Q: q\nA: a\n").unwrap();
+    }
+    let paths = vec![a.clone(), b.clone(), c.clone()];
+
+    let mut cache = FileCache::default();
+    let mut settings = Settings::new(&config_dir, &hooks);
+    assert_eq!(cache.changed(&paths, &mut settings), paths);
+
+    let key = |p: &Path, i| format!("{}#{}", p.display(), i);
+    let mut report = DeckReport::new("Default".to_string());
+    report.notes = vec![
+        (key(&a, 1), NoteChange::Added),
+        (key(&b, 1), NoteChange::Unchanged),
+        (key(&b, 2), NoteChange::Conflict),
+    ];
+    cache.record(&paths, &[report], &mut settings);
+    assert_eq!(cache.get(&a).unwrap().ids, vec![key(&a, 1)]);
+    // conflicts are seen again on the next save
+    assert!(cache.get(&b).is_none());
+    assert!(cache.get(&c).unwrap().ids.is_empty());
+    assert_eq!(cache.changed(&paths, &mut settings), vec![b.clone()]);
+
+    // a different modification time alone doesn't count
+    cache.files.get_mut(&a.display().to_string()).unwrap().modified = 0;
+    fs::write(&c, "Q: other\nA: a\n").unwrap();
+    assert_eq!(cache.changed(&paths, &mut settings), vec![b.clone(), c.clone()]);
+    cache.record(&paths, &[], &mut settings);
+    assert!(cache.changed(&paths, &mut settings).is_empty());
+
+    // defaults only apply to their directory, the config to every file
+    fs::write(dir.join("sub").join(DEFAULTS_FILE), "deck: Sub\n").unwrap();
+    assert_eq!(cache.changed(&paths, &mut Settings::new(&config_dir, &hooks)), vec![c.clone()]);
+    fs::write(config_dir.join("config"), "path_tags = true\n").unwrap();
+    assert_eq!(cache.changed(&paths, &mut Settings::new(&config_dir, &hooks)), paths);
+
+    cache.retain(&paths[..1]);
+    assert_eq!(cache.changed(&[b.clone(), c.clone()], &mut settings), vec![b, c]);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,3 +1,11 @@
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result, parsing::Format};
+
+#[derive(Serialize, Deserialize)]
 pub struct Deck {
     pub name: String,
     pub groups: Vec<TypeGroup>,
@@ -12,16 +20,24 @@ impl Deck {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct TypeGroup {
     pub model: String,
     pub cards: Vec<Card>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Card {
     pub model: String,
     pub fields: Vec<String>, // The first is assumed to be the id
+    #[serde(default)]
     pub tags: Option<String>,
+    #[serde(default)]
+    pub named: Vec<(String, String)>, // (field name, value), placed after the positional fields once the notetype is known
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub media: Vec<(PathBuf, String)>, // (file, name in collection.media)
 }
 
 impl Card {
@@ -30,7 +46,112 @@ impl Card {
             model,
             fields,
             tags,
+            named: vec![],
+            format: Format::default(),
+            media: vec![],
+        }
+    }
+
+    pub fn tag_list(&self) -> Vec<String> {
+        self.tags.as_deref()
+            .map(|t| t.split_whitespace().map(|t| t.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// All fields in the order of the notetype's `names`, with gaps left empty
+    pub fn resolve_fields(&self, names: &[String]) -> Result<Vec<String>> {
+        let id = Some(self.fields[0].clone());
+        let mut fields = self.fields.clone();
+        let mut errors = vec![];
+        for (name, value) in &self.named {
+            let index = match names.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+                Some(i) => i,
+                None => {
+                    errors.push(Error::parse(id.clone(), format!("{} has no field named {}", self.model, name)));
+                    continue;
+                },
+            };
+            if index == 0 {
+                errors.push(Error::parse(id.clone(), format!("{} holds the id and can't be set", name)));
+            } else if index < self.fields.len() || fields.get(index).is_some_and(|f| !f.is_empty()) {
+                errors.push(Error::parse(id.clone(), format!("field {} is set more than once", name)));
+            } else {
+                if fields.len() <= index {
+                    fields.resize(index + 1, String::new());
+                }
+                fields[index] = value.clone();
+            }
+        }
+        if errors.is_empty() {
+            Ok(fields)
+        } else {
+            Err(Error::from_many(errors))
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteChange {
+    Added,
+    Updated,
+    Unchanged,
+    /// Only edited in Anki, so the file is updated instead
+    WrittenBack,
+    /// Edited both in Anki and in the file since the last save
+    Conflict,
+}
+
+/// What happened (or would happen) to each note of a deck during a save
+#[derive(Debug)]
+pub struct DeckReport {
+    pub name: String,
+    pub created: bool,
+    pub notes: Vec<(String, NoteChange)>, // (id, change)
+}
+
+impl DeckReport {
+    pub fn new(name: String) -> DeckReport {
+        DeckReport {
+            name,
+            created: false,
+            notes: vec![],
         }
     }
+
+    pub fn push(&mut self, id: String, change: NoteChange) {
+        self.notes.push((id, change));
+    }
+
+    pub fn count(&self, change: NoteChange) -> i32 {
+        self.notes.iter()
+            .filter(|(_, c)| *c == change)
+            .count() as i32
+    }
 }
 
+/// Groups cards by deck and then by notetype, sorted by name
+pub fn group_cards(cards: Vec<(String, Card)>) -> Vec<Deck> {
+    cards.into_iter()
+        .into_group_map()
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(name, cards)| {
+            let groups = cards.into_iter()
+                .into_group_map_by(|c| c.model.clone())
+                .into_iter()
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(model, cards)| TypeGroup { model, cards })
+                .collect();
+            Deck::new(name, groups)
+        })
+        .collect()
+}
+
+/// Splits a generated id like `path/to/file.qz#2` into the file and the note's position
+pub fn split_id(id: &str) -> (&str, Option<&str>) {
+    match id.rsplit_once('#') {
+        Some((file, index)) if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) => (file, Some(index)),
+        _ => (id, None),
+    }
+}
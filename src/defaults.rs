@@ -0,0 +1,116 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::{Error, Result};
+
+pub const DEFAULTS_FILE: &str = "_defaults.yaml";
+
+/// Frontmatter defaults from `.anc/defaults` and the `_defaults.yaml` of every directory
+///
+/// The defaults of a file are `.anc/defaults` overlaid with the `_defaults.yaml` of each directory from the one
+/// containing `.anc` down to its own, so nearer directories win. Tags are concatenated instead.
+pub struct Defaults {
+    base_dir: PathBuf,
+    project: Mapping,
+    dirs: HashMap<PathBuf, Mapping>, // merged defaults of each directory read so far
+}
+
+impl Defaults {
+    pub fn load(config_dir: &Path) -> Result<Defaults> {
+        Ok(Defaults {
+            base_dir: config_dir.parent().unwrap().to_path_buf(),
+            project: read_defaults(&config_dir.join("defaults"))?,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Only `.anc/defaults`, for notes without a file
+    pub fn project(&self) -> &Mapping {
+        &self.project
+    }
+
+    pub fn for_file(&mut self, path: &Path) -> Result<Mapping> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if !dir.starts_with(&self.base_dir) {
+            return Ok(self.project.clone());
+        }
+        self.for_dir(dir)
+    }
+
+    fn for_dir(&mut self, dir: &Path) -> Result<Mapping> {
+        if let Some(defaults) = self.dirs.get(dir) {
+            return Ok(defaults.clone());
+        }
+        let parent = match dir.parent() {
+            Some(parent) if dir != self.base_dir => self.for_dir(parent)?,
+            _ => self.project.clone(),
+        };
+        let defaults = merge(&parent, read_defaults(&dir.join(DEFAULTS_FILE))?);
+        self.dirs.insert(dir.to_path_buf(), defaults.clone());
+        Ok(defaults)
+    }
+}
+
+fn read_defaults(path: &Path) -> Result<Mapping> {
+    let text = match fs::read_to_string(path) {
+        Err(_) => return Ok(Mapping::new()),
+        Ok(text) => text,
+    };
+    let file = Some(path.display().to_string());
+    match serde_yaml::from_str(&text) {
+        Ok(Value::Mapping(defaults)) => Ok(defaults),
+        Ok(Value::Null) => Ok(Mapping::new()),
+        Ok(_) => Err(Error::parse(file, "defaults have to be a mapping like the frontmatter")),
+        Err(e) => Err(match e.location() {
+            Some(l) => Error::parse_at(file, l.line(), l.column(), text.lines().nth(l.line() - 1).map(|l| l.to_string()), e.to_string()),
+            None => Error::parse(file, e.to_string()),
+        }),
+    }
+}
+
+/// Lays `overrides` over `defaults`, except for tags, which are appended
+pub fn merge(defaults: &Mapping, overrides: Mapping) -> Mapping {
+    let mut merged = defaults.clone();
+    let tags = Value::String("tags".to_string());
+    for (key, value) in overrides {
+        let value = match (merged.get(&key), value) {
+            (Some(Value::String(old)), Value::String(new)) if key == tags => Value::String(format!("{} {}", old, new)),
+            (Some(old), new) if key == tags => match (tag_list(old), tag_list(&new)) {
+                (Some(mut old), Some(mut new)) => {
+                    old.append(&mut new);
+                    Value::Sequence(old)
+                },
+                _ => new,
+            },
+            (_, value) => value,
+        };
+        merged.insert(key, value);
+    }
+    merged
+}
+
+fn tag_list(tags: &Value) -> Option<Vec<Value>> {
+    match tags {
+        Value::String(line) => Some(line.split_whitespace().map(|t| Value::String(t.to_string())).collect()),
+        Value::Sequence(list) => Some(list.clone()),
+        _ => None,
+    }
+}
+
+#[test]
+fn nearer_wins() {
+    let config_dir = Path::new("test_files/defaults/.anc").canonicalize().unwrap();
+    let mut defaults = Defaults::load(&config_dir).unwrap();
+
+    let get = |defaults: &Mapping, key: &str| defaults.get(&Value::String(key.to_string())).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+    let merged = defaults.for_file(&config_dir.parent().unwrap().join("biology/cells/a.qz")).unwrap();
+    assert_eq!(get(&merged, "deck").as_deref(), Some("Biology::Cells"));
+    assert_eq!(get(&merged, "type").as_deref(), Some("basic"));
+    assert_eq!(get(&merged, "tags").as_deref(), Some("anc biology cells"));
+
+    let merged = defaults.for_file(&config_dir.parent().unwrap().join("top.qz")).unwrap();
+    assert_eq!(get(&merged, "deck").as_deref(), Some("Default"));
+    assert_eq!(get(&merged, "tags").as_deref(), Some("anc"));
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use anki::collection::CollectionBuilder;
+use rusqlite::{params, OptionalExtension};
+use similar::{ChangeTag, TextDiff};
+
+use crate::{Error, Result, get_config, collect_cards, build_field_str, resolve_note, cards::NoteChange, state::{SyncState, Resolution, merge_tags, same_tags}};
+
+/// The differences between a note in the collection and the card it was parsed from
+pub struct NoteDiff {
+    pub deck: String,
+    pub id: String,
+    /// What saving would do: `Updated`, `WrittenBack` or `Conflict`
+    pub change: NoteChange,
+    pub fields: Vec<(String, String, String)>, // (field name, stored, parsed)
+    pub tags: Option<(String, String)>, // (stored, parsed)
+}
+
+impl NoteDiff {
+    /// The changes saving makes, to the file for notes written back and to the collection otherwise
+    pub fn unified(&self) -> Vec<String> {
+        let written_back = self.change == NoteChange::WrittenBack;
+        let mut lines = match written_back {
+            true => vec![format!("--- {}", self.id), format!("+++ collection/{}", self.id)],
+            false => vec![format!("--- collection/{}", self.id), format!("+++ {}", self.id)],
+        };
+        if self.change == NoteChange::Conflict {
+            lines.push("# edited both in Anki and in the file, so saving skips it".to_string());
+        }
+        for (name, stored, parsed) in &self.fields {
+            lines.push(format!("@@ {} @@", name));
+            match written_back {
+                true => lines.append(&mut diff_lines(parsed, stored)),
+                false => lines.append(&mut diff_lines(stored, parsed)),
+            }
+        }
+        if let Some((stored, parsed)) = &self.tags {
+            lines.push("@@ tags @@".to_string());
+            lines.append(&mut diff_lines(stored, parsed));
+        }
+        lines
+    }
+}
+
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    TextDiff::from_lines(old, new)
+        .iter_all_changes()
+        .map(|change| {
+            let sign = match change.tag() {
+                ChangeTag::Delete => "-",
+                ChangeTag::Insert => "+",
+                ChangeTag::Equal => " ",
+            };
+            format!("{}{}", sign, change.value().trim_end_matches('\n'))
+        })
+        .collect()
+}
+
+/// Compares every parsed card with the note sharing its id, without changing the collection
+///
+/// Like when saving, notes only edited in Anki are shown as written back into their file, and notes edited on both
+/// sides as conflicts.
+pub fn diff() -> Result<Vec<NoteDiff>> {
+    let config = get_config()?;
+
+    let decks = collect_cards(&config)?;
+    let state = SyncState::load(&config.config_dir)?;
+
+    let collection = CollectionBuilder::new(config.anki_dir.join("collection.anki2")).build()?;
+    let db = &collection.storage.db;
+    let mut type_query = db.prepare(
+        "
+            SELECT nt.id, count(*)
+            FROM notetypes nt
+            join fields fd on fd.ntid = nt.id
+            WHERE nt.name like ?
+            GROUP BY nt.id
+        ")?;
+    let mut field_names = db.prepare("SELECT name FROM fields WHERE ntid = ? ORDER BY ord")?;
+    let mut existing_note = db.prepare(
+        "
+            SELECT flds, tags, mod
+            FROM notes
+            WHERE SUBSTR(flds, 0, INSTR(flds, char(31))) like ?
+            limit 1
+        ")?;
+
+    let mut models: HashMap<String, (usize, Vec<String>)> = HashMap::new();
+    let mut diffs = vec![];
+    for d in decks {
+        for g in d.groups {
+            if !models.contains_key(&g.model) {
+                let (type_id, field_count): (i64, usize) = type_query.query_row(params![g.model], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .optional()?
+                    .ok_or_else(|| Error::MissingNotetype(g.model.clone()))?;
+                let names = field_names.query_map(params![type_id], |row| row.get(0))?
+                    .collect::<std::result::Result<Vec<String>, _>>()?;
+                models.insert(g.model.clone(), (field_count, names));
+            }
+            let (field_count, names) = models.get(&g.model).unwrap();
+
+            for card in g.cards {
+                let id = card.fields[0].clone();
+                let existing = existing_note.query_row(params![id], |row| Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, String>(1)?,
+                    row.get::<usize, i64>(2)?,
+                )));
+                let (stored_flds, stored_tags, stored_mod) = match existing.optional()? {
+                    Some(e) => e,
+                    None => continue, // new notes have nothing to diff against
+                };
+
+                let resolved = card.resolve_fields(names)?;
+                let parsed_flds = build_field_str(&resolved, *field_count, resolved.len());
+                let change = match resolve_note(&card, &parsed_flds, &stored_flds, stored_mod, Some(&state), false).0 {
+                    Resolution::FromFile => NoteChange::Updated,
+                    Resolution::WriteBack => NoteChange::WrittenBack,
+                    Resolution::Conflict => NoteChange::Conflict,
+                };
+                let fields: Vec<_> = stored_flds.split('\u{1f}')
+                    .zip(parsed_flds.split('\u{1f}'))
+                    .enumerate()
+                    .filter(|(_, (stored, parsed))| stored != parsed)
+                    .map(|(i, (stored, parsed))| (
+                        names.get(i).cloned().unwrap_or_else(|| format!("Field {}", i + 1)),
+                        stored.to_string(),
+                        parsed.to_string(),
+                    ))
+                    .collect();
+                // tags added in Anki are kept when saving, so they aren't a difference, and only updates change tags
+                let parsed_tags = merge_tags(&stored_tags, state.owned_tags(&id), &card.tag_list(), config.path_tags);
+                let tags = if change == NoteChange::Updated && !same_tags(&stored_tags, &parsed_tags) {
+                    Some((stored_tags.trim().to_string(), parsed_tags.join(" ")))
+                } else {
+                    None
+                };
+
+                if !fields.is_empty() || tags.is_some() {
+                    diffs.push(NoteDiff {
+                        deck: d.name.clone(),
+                        id,
+                        change,
+                        fields,
+                        tags,
+                    });
+                }
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+#[test]
+fn directions() {
+    let note = |change| NoteDiff {
+        deck: "example".to_string(),
+        id: "a.qz#1".to_string(),
+        change,
+        fields: vec![("Front".to_string(), "anki".to_string(), "file".to_string())],
+        tags: None,
+    };
+
+    assert_eq!(note(NoteChange::Updated).unified(), vec!["--- collection/a.qz#1", "+++ a.qz#1", "@@ Front @@", "-anki", "+file"]);
+    assert_eq!(note(NoteChange::WrittenBack).unified(), vec!["--- a.qz#1", "+++ collection/a.qz#1", "@@ Front @@", "-file", "+anki"]);
+    assert_eq!(note(NoteChange::Conflict).unified()[2], "# edited both in Anki and in the file, so saving skips it");
+}
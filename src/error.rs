@@ -0,0 +1,132 @@
+use std::{fmt, io, path::PathBuf};
+
+use anki::error::AnkiError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Missing or invalid `.anc` configuration
+    Config(String),
+    /// A .qz file or hook output that can't be turned into notes
+    Parse {
+        file: Option<String>,
+        line: Option<usize>, // 1-based, like column
+        column: Option<usize>,
+        snippet: Option<String>, // the source line
+        message: String,
+    },
+    /// A hook that couldn't be run or that failed
+    Hook {
+        hook: String,
+        message: String,
+    },
+    /// The collection couldn't be opened, read or written
+    Collection(String),
+    MissingDeck(String),
+    MissingNotetype(String),
+    Sync(String),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// Several independent failures, e.g. one per file
+    Multiple(Vec<Error>),
+}
+
+impl Error {
+    pub fn parse<S: Into<String>>(file: Option<String>, message: S) -> Error {
+        Error::Parse {
+            file,
+            line: None,
+            column: None,
+            snippet: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn parse_at<S: Into<String>>(file: Option<String>, line: usize, column: usize, snippet: Option<String>, message: S) -> Error {
+        Error::Parse {
+            file,
+            line: Some(line),
+            column: Some(column),
+            snippet,
+            message: message.into(),
+        }
+    }
+
+    pub fn io<P: Into<PathBuf>>(path: P, error: io::Error) -> Error {
+        Error::Io {
+            path: path.into(),
+            error,
+        }
+    }
+
+    /// A single error stays as it is, more are wrapped in `Multiple`
+    pub fn from_many(mut errors: Vec<Error>) -> Error {
+        if errors.len() == 1 {
+            errors.remove(0)
+        } else {
+            Error::Multiple(errors)
+        }
+    }
+
+    /// Every error, with `Multiple` flattened
+    pub fn errors(&self) -> Vec<&Error> {
+        match self {
+            Error::Multiple(errors) => errors.iter().flat_map(|e| e.errors()).collect(),
+            e => vec![e],
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "{}", message),
+            Error::Parse { file, line, column, snippet, message } => {
+                let position = match (line, column) {
+                    (Some(line), Some(column)) => Some(format!("{}:{}", line, column)),
+                    (Some(line), None) => Some(line.to_string()),
+                    _ => None,
+                };
+                match (file, position) {
+                    (Some(file), Some(position)) => write!(f, "{}:{}: ", file, position)?,
+                    (Some(file), None) => write!(f, "{}: ", file)?,
+                    (None, Some(position)) => write!(f, "line {}: ", position)?,
+                    (None, None) => {},
+                }
+                write!(f, "{}", message)?;
+                if let (Some(line), Some(snippet)) = (line, snippet) {
+                    let width = line.to_string().len();
+                    write!(f, "\n{:width$} |\n{} | {}", "", line, snippet, width = width)?;
+                    if let Some(column) = column {
+                        write!(f, "\n{:width$} | {:indent$}^", "", "", width = width, indent = column.saturating_sub(1))?;
+                    }
+                }
+                Ok(())
+            },
+            Error::Hook { hook, message } => write!(f, "{} hook: {}", hook, message),
+            Error::Collection(message) => write!(f, "{}", message),
+            Error::MissingDeck(name) => write!(f, "Deck {} does not exist. Create it or save with --create-decks", name),
+            Error::MissingNotetype(name) => write!(f, "Notetype {} does not exist", name),
+            Error::Sync(message) => write!(f, "{}", message),
+            Error::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::Multiple(errors) => write!(f, "{}", errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Collection(e.to_string())
+    }
+}
+
+impl From<AnkiError> for Error {
+    fn from(e: AnkiError) -> Error {
+        Error::Collection(e.to_string())
+    }
+}
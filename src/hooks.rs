@@ -0,0 +1,434 @@
+use std::{collections::BTreeMap, fs, io::{BufRead, Read, Write}, path::{Path, PathBuf}, process::{Command, Stdio}, thread, time::{Duration, Instant}};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Mapping;
+
+use crate::{Error, Result, cards::{Deck, DeckReport, NoteChange, group_cards}, defaults::Defaults, parsing::{BatchReader, Format, card_from_parts}};
+
+pub const PRE_PARSE: &str = "pre-parse";
+pub const POST_PARSE: &str = "post-parse";
+pub const PRE_SAVE: &str = "pre-save";
+pub const POST_SAVE: &str = "post-save";
+pub const PRE_SYNC: &str = "pre-sync";
+pub const POST_SYNC: &str = "post-sync";
+
+/// How long hooks may run if `hook_timeout` isn't set in `.anc/config`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The line in the first lines of a hook that picks its protocol, e.g. `# anc-hook-protocol: 2`
+const PROTOCOL_HEADER: &str = "anc-hook-protocol:";
+
+/// How anc talks to a `pre-parse` hook
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// Newline separated paths in, notes in the .qz format with `###` in between out
+    V1,
+    /// JSON lines in both directions
+    V2,
+}
+
+impl Protocol {
+    pub fn from_version(version: u8) -> Result<Protocol> {
+        match version {
+            1 => Ok(Protocol::V1),
+            2 => Ok(Protocol::V2),
+            v => Err(Error::Config(format!("Unknown hook protocol {}, use 1 or 2", v))),
+        }
+    }
+
+    /// The protocol named in the header of the hook at `path`, or `configured` if it has none
+    fn of_hook(path: &Path, configured: Protocol) -> Result<Protocol> {
+        let script = fs::read(path).map_err(|e| Error::io(path, e))?;
+        let version = String::from_utf8_lossy(&script[..script.len().min(1024)])
+            .lines()
+            .take(5)
+            .find_map(|l| l.find(PROTOCOL_HEADER).map(|i| l[i + PROTOCOL_HEADER.len()..].trim().to_string()));
+        match version {
+            None => Ok(configured),
+            Some(v) => Protocol::from_version(v.parse().map_err(|_| hook_error(PRE_PARSE, format!("invalid protocol version {}", v)))?),
+        }
+    }
+}
+
+/// A line written to a v2 hook: a file to parse and the defaults of its notes
+#[derive(Serialize)]
+struct FileInput<'a> {
+    path: &'a str,
+    frontmatter: &'a Mapping,
+}
+
+/// What a save did to a deck, as sent to the `post-save` hook
+#[derive(Serialize)]
+struct SavedDeck<'a> {
+    deck: &'a str,
+    added: Vec<&'a str>,
+    updated: Vec<&'a str>,
+}
+
+/// A line read from a v2 hook
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Output {
+    Error {
+        path: String,
+        error: String,
+        #[serde(default)]
+        line: Option<usize>,
+    },
+    Note {
+        path: String,
+        #[serde(default)]
+        fields: Vec<String>,
+        #[serde(default)]
+        named: BTreeMap<String, String>,
+        /// `deck`, `type`, `tags` and the other frontmatter keys
+        #[serde(flatten)]
+        frontmatter: Mapping,
+    },
+}
+
+fn ids(report: &DeckReport, change: NoteChange) -> Vec<&str> {
+    report.notes.iter()
+        .filter(|(_, c)| *c == change)
+        .map(|(id, _)| id.as_str())
+        .collect()
+}
+
+fn to_json<T: Serialize>(hook: &str, value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| hook_error(hook, format!("can't send the input: {}", e)))
+}
+
+fn hook_error<S: Into<String>>(hook: &str, message: S) -> Error {
+    Error::Hook {
+        hook: hook.to_string(),
+        message: message.into(),
+    }
+}
+
+/// The scripts in `.anc/hooks` and how to run them
+pub struct Hooks {
+    dir: PathBuf,
+    /// Of `pre-parse` hooks without a protocol header
+    protocol: Protocol,
+    /// After which a hook is killed
+    timeout: Duration,
+    converters: Converters,
+}
+
+impl Hooks {
+    pub fn new(config_dir: &Path, protocol: Protocol, timeout: Duration, converters: Converters) -> Hooks {
+        Hooks {
+            dir: config_dir.join("hooks"),
+            protocol,
+            timeout,
+            converters,
+        }
+    }
+
+    /// Whether a converter is registered for `path`
+    pub fn converts(&self, path: &Path) -> bool {
+        self.converter(path).is_some()
+    }
+
+    /// The converter registered for `path`
+    pub fn converter(&self, path: &Path) -> Option<&Path> {
+        self.converters.find(path)
+    }
+
+    /// The .qz text the converter registered for `path` makes of it, or `None` without one
+    pub fn convert(&self, path: &Path) -> Result<Option<String>> {
+        let converter = match self.converters.find(path) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let input = fs::read(path).map_err(|e| Error::io(path, e))?;
+        let output = run(converter, &[path.display().to_string()], input, self.timeout)?;
+        String::from_utf8(output)
+            .map(Some)
+            .map_err(|_| Error::parse(Some(path.display().to_string()), format!("{} printed invalid UTF-8", converter.display())))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.dir.join(name).is_file()
+    }
+
+    /// Turns `paths` into decks with the `pre-parse` hook
+    pub fn pre_parse(&self, paths: &[PathBuf], format: Format, defaults: Defaults) -> Result<Vec<Deck>> {
+        pre_parse(&self.dir.join(PRE_PARSE), paths, format, defaults, self.protocol, self.timeout)
+    }
+
+    /// Passes the parsed decks through the `post-parse` hook, which may print changed ones or nothing to keep them
+    pub fn post_parse(&self, decks: Vec<Deck>) -> Result<Vec<Deck>> {
+        let output = match self.run_if_present(POST_PARSE, to_json(POST_PARSE, &decks)?)? {
+            Some(output) if !String::from_utf8_lossy(&output).trim().is_empty() => output,
+            _ => return Ok(decks),
+        };
+        serde_json::from_slice(&output)
+            .map_err(|e| hook_error(POST_PARSE, format!("printed something other than decks: {}", e)))
+    }
+
+    /// Shows the decks about to be saved to the `pre-save` hook, which stops the save by failing
+    pub fn pre_save(&self, decks: &[Deck]) -> Result<()> {
+        self.run_if_present(PRE_SAVE, to_json(PRE_SAVE, &decks)?).map(|_| ())
+    }
+
+    /// Tells the `post-save` hook which notes were added and updated in each deck
+    pub fn post_save(&self, reports: &[DeckReport]) -> Result<()> {
+        if !self.has(POST_SAVE) {
+            return Ok(());
+        }
+        let saved: Vec<SavedDeck> = reports.iter()
+            .map(|r| SavedDeck {
+                deck: &r.name,
+                added: ids(r, NoteChange::Added),
+                updated: ids(r, NoteChange::Updated),
+            })
+            .collect();
+        self.run_if_present(POST_SAVE, to_json(POST_SAVE, &saved)?).map(|_| ())
+    }
+
+    /// Runs the `pre-sync` hook, which stops the sync by failing
+    pub fn pre_sync(&self) -> Result<()> {
+        self.run_if_present(PRE_SYNC, vec![]).map(|_| ())
+    }
+
+    pub fn post_sync(&self) -> Result<()> {
+        self.run_if_present(POST_SYNC, vec![]).map(|_| ())
+    }
+
+    /// The stdout of the hook called `name`, or `None` if there is no such hook
+    fn run_if_present(&self, name: &str, input: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.has(name) {
+            run(&self.dir.join(name), &[], input, self.timeout).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Hooks that turn files of other formats into .qz text, by globs relative to the directory containing `.anc`
+///
+/// A file goes through the converter of the first matching glob in alphabetical order.
+pub struct Converters {
+    base_dir: PathBuf,
+    globs: GlobSet,
+    hooks: Vec<PathBuf>, // by the index of their glob
+}
+
+impl Converters {
+    /// `converters` maps globs to paths relative to `config_dir`, like `hooks/md2qz`
+    pub fn new(config_dir: &Path, converters: BTreeMap<String, String>) -> Result<Converters> {
+        let mut globs = GlobSetBuilder::new();
+        let mut hooks = vec![];
+        for (glob, hook) in converters {
+            globs.add(Glob::new(&glob).map_err(|e| Error::Config(format!("Invalid converter glob in .anc/config: {}", e)))?);
+            hooks.push(config_dir.join(hook));
+        }
+        Ok(Converters {
+            base_dir: config_dir.parent().unwrap().to_path_buf(),
+            globs: globs.build().map_err(|e| Error::Config(format!("Invalid converter glob in .anc/config: {}", e)))?,
+            hooks,
+        })
+    }
+
+    fn find(&self, path: &Path) -> Option<&Path> {
+        let relative = path.strip_prefix(&self.base_dir).unwrap_or(path);
+        self.globs.matches(relative).into_iter()
+            .min()
+            .map(|i| self.hooks[i].as_path())
+    }
+}
+
+/// Runs `hook` with `args` and `input` as its stdin and returns its stdout
+///
+/// Fails if it exits with an error, including its stderr, or if it takes longer than `timeout`, in which case it's
+/// killed.
+pub fn run(hook: &Path, args: &[String], input: Vec<u8>, timeout: Duration) -> Result<Vec<u8>> {
+    let name = hook.file_name().unwrap().to_string_lossy().to_string();
+    // relative paths would be resolved from the hook's directory
+    let hook = hook.canonicalize().map_err(|e| hook_error(&name, format!("could not be started: {}", e)))?;
+    let mut process = Command::new(&hook)
+        .args(args)
+        .current_dir(hook.parent().unwrap())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| hook_error(&name, format!("could not be started: {}", e)))?;
+
+    // the pipes are served by threads, so a hook filling one of them can't block on us
+    let mut stdin = process.stdin.take().unwrap();
+    thread::spawn(move || stdin.write_all(&input));
+    let read_all = |mut pipe: Box<dyn Read + Send>| thread::spawn(move || {
+        let mut buffer = vec![];
+        pipe.read_to_end(&mut buffer).map(|_| buffer)
+    });
+    let stdout = read_all(Box::new(process.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(process.stderr.take().unwrap()));
+
+    let start = Instant::now();
+    let status = loop {
+        match process.try_wait() {
+            Err(e) => return Err(hook_error(&name, format!("could not be waited for: {}", e))),
+            Ok(Some(status)) => break status,
+            Ok(None) if start.elapsed() > timeout => {
+                // its output is left behind, since children of the hook might keep the pipes open
+                let _ = process.kill();
+                let _ = process.wait();
+                return Err(hook_error(&name, format!("killed after running for more than {} seconds", timeout.as_secs_f32())));
+            },
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let stderr = stderr.join().unwrap().unwrap_or_default();
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(hook_error(&name, match stderr.trim() {
+            "" => format!("failed with {}", status),
+            stderr => format!("failed with {}:\n{}", status, stderr),
+        }));
+    }
+    stdout.join().unwrap()
+        .map_err(|e| hook_error(&name, format!("could not read the output: {}", e)))
+}
+
+/// Turns `paths` into decks with the `pre-parse` hook at `hook`
+fn pre_parse(hook: &Path, paths: &[PathBuf], format: Format, mut defaults: Defaults, protocol: Protocol, timeout: Duration) -> Result<Vec<Deck>> {
+    let protocol = Protocol::of_hook(hook, protocol)?;
+    let input = match protocol {
+        Protocol::V1 => paths.iter()
+            .map(|p| format!("{}\n", p.display()))
+            .collect(),
+        Protocol::V2 => paths.iter()
+            .map(|p| {
+                let frontmatter = defaults.for_file(p)?;
+                let line = serde_json::to_string(&FileInput { path: &p.display().to_string(), frontmatter: &frontmatter })
+                    .map_err(|e| hook_error(PRE_PARSE, format!("can't send the defaults of {}: {}", p.display(), e)))?;
+                Ok(format!("{}\n", line))
+            })
+            .collect::<Result<String>>()?,
+    };
+
+    let output = run(hook, &[], input.into_bytes(), timeout)?;
+    match protocol {
+        Protocol::V1 => BatchReader::from_hook(&output)
+            .with_format(format)
+            .with_defaults(defaults)
+            .parse(),
+        Protocol::V2 => read_notes(output.as_slice(), format, &mut defaults),
+    }
+}
+
+/// Collects the notes of a v2 hook, numbering the ones without an id per file like .qz notes
+fn read_notes<R: BufRead>(output: R, format: Format, defaults: &mut Defaults) -> Result<Vec<Deck>> {
+    let mut cards = vec![];
+    let mut errors = vec![];
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for (i, line) in output.lines().enumerate() {
+        let line = line.map_err(|e| hook_error(PRE_PARSE, format!("could not read the output: {}", e)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(Output::Error { path, error, line }) => errors.push(Error::Parse {
+                file: Some(path),
+                line,
+                column: None,
+                snippet: None,
+                message: error,
+            }),
+            Ok(Output::Note { path, fields, named, frontmatter }) => {
+                let count = counts.entry(path.clone()).or_insert(0);
+                *count += 1;
+                let note_defaults = defaults.for_file(Path::new(&path))?;
+                let id = format!("{}#{}", path, count);
+                match card_from_parts(frontmatter, &note_defaults, id, format, fields, named.into_iter().collect()) {
+                    Ok(card) => cards.push(card),
+                    Err(message) => errors.push(Error::parse(Some(path), format!("note {}: {}", count, message))),
+                }
+            },
+            Err(e) => errors.push(hook_error(PRE_PARSE, format!("line {} of the output is neither a note nor an error: {}", i + 1, e))),
+        }
+    }
+    if errors.is_empty() {
+        Ok(group_cards(cards))
+    } else {
+        Err(Error::from_many(errors))
+    }
+}
+
+#[test]
+fn json_notes() {
+    let config_dir = Path::new("test_files/defaults/.anc").canonicalize().unwrap();
+    let mut defaults = Defaults::load(&config_dir).unwrap();
+    let file = config_dir.parent().unwrap().join("biology/cells/a.md").display().to_string();
+    let output = [
+        format!(r#"{{"path": "{}", "fields": ["Front", "**Back**"], "format": "markdown"}}"#, file),
+        String::new(),
+        format!(r#"{{"path": "{}", "id": "mine", "named": {{"Back": "b"}}, "tags": ["x"]}}"#, file),
+    ].join("\n");
+
+    let decks = read_notes(output.as_bytes(), Format::Plain, &mut defaults).unwrap();
+    assert_eq!(decks.len(), 1);
+    assert_eq!(decks[0].name, "Biology::Cells");
+    let cards = &decks[0].groups[0].cards;
+    assert_eq!(cards[0].fields, vec![format!("{}#1", file), "Front".to_string(), "<strong>Back</strong>".to_string()]);
+    assert_eq!(cards[0].tags.as_deref(), Some("anc biology cells"));
+    assert_eq!(cards[1].fields, vec!["mine".to_string()]);
+    assert_eq!(cards[1].named, vec![("Back".to_string(), "b".to_string())]);
+    assert_eq!(cards[1].tags.as_deref(), Some("anc biology cells x"));
+
+    let output = format!("{{\"path\": \"{}\", \"error\": \"no question\", \"line\": 3}}\nnot json", file);
+    let error = read_notes(output.as_bytes(), Format::Plain, &mut defaults).err().unwrap();
+    assert_eq!(error.errors().len(), 2);
+    assert_eq!(error.errors()[0].to_string(), format!("{}:3: no question", file));
+}
+
+#[test]
+fn failing_hooks() {
+    let result = run(Path::new("test_files/hooks/fail"), &[], b"input".to_vec(), DEFAULT_TIMEOUT);
+    assert_eq!(result.err().unwrap().to_string(), "fail hook: failed with exit status: 3:\nno notes in input");
+
+    let start = Instant::now();
+    let result = run(Path::new("test_files/hooks/hang"), &[], vec![], Duration::from_millis(100));
+    assert!(result.is_err(), "A hung hook is allowed");
+    assert!(start.elapsed() < Duration::from_secs(5), "The hook wasn't killed");
+
+    let output = run(Path::new("test_files/hooks/echo"), &[], b"a\nb\n".to_vec(), DEFAULT_TIMEOUT).unwrap();
+    assert_eq!(output, b"a\nb\n");
+}
+
+#[test]
+fn post_parse() {
+    let hooks = Hooks::new(Path::new("test_files"), Protocol::V1, DEFAULT_TIMEOUT, Converters::new(Path::new("test_files"), BTreeMap::new()).unwrap());
+    let decks = BatchReader::from_string(vec![(
+        "a.qz".to_string(),
+        "---\ndeck: Default\ntype: basic\n---\nfront\n---\nback\n",
+    )]).parse().unwrap();
+
+    let decks = hooks.post_parse(decks).unwrap();
+    assert_eq!(decks[0].name, "Renamed");
+    assert_eq!(decks[0].groups[0].cards[0].fields, vec!["a.qz#1", "front", "back"]);
+    assert!(hooks.pre_save(&decks).is_ok());
+}
+
+#[test]
+fn converters() {
+    use crate::parsing::parse_files;
+
+    let config_dir = Path::new("test_files/convert/.anc").canonicalize().unwrap();
+    let converters = Converters::new(&config_dir, BTreeMap::from([("*.txt".to_string(), "hooks/txt2qz".to_string())])).unwrap();
+    let hooks = Hooks::new(&config_dir, Protocol::V1, DEFAULT_TIMEOUT, converters);
+    let file = config_dir.parent().unwrap().join("cells.txt");
+    assert!(hooks.converts(&file));
+    assert!(!hooks.converts(&config_dir.parent().unwrap().join("cells.qz")));
+
+    let decks = parse_files(config_dir.clone(), vec![file.clone()], Format::Plain, &hooks).unwrap();
+    assert_eq!(decks[0].groups[0].cards[0].fields, vec![
+        format!("{}#1", file.display()),
+        "Mitochondria".to_string(),
+        "The powerhouse<br/>of the cell".to_string(),
+    ]);
+}
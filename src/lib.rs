@@ -1,79 +1,225 @@
-use std::{fs, collections::HashMap, time::SystemTime, path::{PathBuf, Path}, env};
+use std::{fs, collections::{BTreeMap, HashMap}, time::{SystemTime, Duration}, path::{PathBuf, Path}, env, io::Write};
 
-use anki::{notes::NoteId, collection::CollectionBuilder, timestamp::TimestampSecs, decks::{DeckKindContainer, DeckKind, DeckId}, prelude::DeckConfigId, deckconfig::NewCardInsertOrder};
-use itertools::{Either, Itertools};
+use anki::{notes::NoteId, collection::{Collection, CollectionBuilder}, timestamp::TimestampSecs, decks::{DeckKindContainer, DeckKind, DeckId, NativeDeckName}, prelude::DeckConfigId, deckconfig::NewCardInsertOrder};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use itertools::Itertools;
 use prost::Message;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use serde::Deserialize;
 use tfio::{Transaction, RollbackableOperation};
 use uuid::Uuid;
 
 pub mod pb;
+pub mod error;
 pub mod cards;
 pub mod parsing;
 pub mod sync;
+pub mod diff;
+pub mod prune;
+pub mod pull;
+pub mod state;
+pub mod cache;
+pub mod watch;
+pub mod notetypes;
+pub mod math;
+pub mod defaults;
+pub mod media;
+pub mod hooks;
 
-use parsing::parse_files;
-use cards::Deck;
+use parsing::{parse_files, Format};
+use cards::{Deck, DeckReport, NoteChange, Card, split_id, group_cards};
+use state::{SyncState, Resolution, write_back};
+use cache::{FileCache, Settings};
+use media::{resolve_media, add_media, restore_references};
+use hooks::{Converters, Hooks, Protocol, DEFAULT_TIMEOUT};
+pub use error::{Error, Result};
 
-pub fn init() -> Result<Vec<String>, Vec<String>> {
+/// Start of the tags made from file paths, kept apart from anc's other tags like `anc::orphan`
+pub const PATH_TAG_PREFIX: &str = "anc::path";
+/// Of the files anc reads without a converter
+pub const QZ_EXTENSION: &str = "qz";
+
+/// Creates `.anc` in the current directory
+///
+/// With `notetypes`, id-aware copies of the stock notetypes are added to the collection in `anki_dir` (or `$ANKI_DIR`)
+/// and recorded in the config, so that e.g. `type: basic` uses them.
+pub fn init(anki_dir: Option<PathBuf>, notetypes: bool) -> Result<Vec<String>> {
     let to_create = env::current_dir()
-        .map_err(|e| vec![e.to_string()])?
+        .map_err(|e| Error::io(".", e))?
         .join(".anc");
+    if to_create.exists() {
+        return Err(Error::Config(format!("{} already exists", to_create.to_string_lossy())));
+    }
+
+    let notetype_dir = match notetypes {
+        true => Some(
+            anki_dir.clone()
+                .or(env::var("ANKI_DIR").map_or(None, |ad| Some(PathBuf::from(ad))))
+                .ok_or(Error::Config("Pass --anki-dir or set $ANKI_DIR to add notetypes".to_string()))?
+        ),
+        false => None,
+    };
+    let config = match &anki_dir {
+        Some(dir) => format!("anki_dir = {}\n", toml::Value::String(dir.display().to_string())),
+        None => "# anki_dir = \"~/.local/share/Anki2/User 1\"\n".to_string(),
+    };
+
     let mut tran = Transaction::new()
         .create_dir("./.anc")
         .create_dir("./.anc/hooks")
         .create_file("./.anc/config")
-        .write_file("./.anc/config", "/tmp", b"# anki_dir = \"~/.local/share/Anki2/User 1\"\n".to_vec());
-    match tran.execute() {
-        Err(e) => {
-            let mut errors = vec![e.to_string(), "Error creating .anc directory".to_string()];
-            if let Err(_) = tran.rollback() {
-                errors.push("Error undoing failure".to_string());
-            }
-            Err(errors)
-        },
-        Ok(_) => {
-            Ok(vec![format!("Initialized empty anc configuration in {}", to_create.to_string_lossy())])
+        .write_file("./.anc/config", "/tmp", config.into_bytes());
+    let undo = |mut errors: Vec<Error>, tran: &mut Transaction| {
+        if let Err(e) = tran.rollback() {
+            errors.push(Error::Config(format!("Error undoing failure: {}", e)));
         }
+        Error::from_many(errors)
+    };
+    if let Err(e) = tran.execute() {
+        return Err(undo(vec![Error::Config(format!("Error creating .anc directory: {}", e))], &mut tran));
     }
+
+    let mut output = vec![format!("Initialized empty anc configuration in {}", to_create.to_string_lossy())];
+    if let Some(anki_dir) = notetype_dir {
+        // only touch the collection once .anc exists, so a failure here can still be undone by removing it
+        let variants = notetypes::add_stock_variants(&anki_dir)
+            .and_then(|variants| {
+                let line = format!(
+                    "notetypes = {{ {} }}\n",
+                    variants.iter()
+                        .map(|(key, name)| format!("{} = {}", key, toml::Value::String(name.to_string())))
+                        .join(", ")
+                );
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(to_create.join("config"))
+                    .and_then(|mut f| f.write_all(line.as_bytes()))
+                    .map_err(|e| Error::io(to_create.join("config"), e))?;
+                Ok(variants)
+            })
+            .map_err(|e| undo(vec![e], &mut tran))?;
+        output.extend(variants.into_iter().map(|(key, name)| format!("Notetype {} is available as type: {}", name, key)));
+    }
+    Ok(output)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct Config {
     anki_dir: Option<PathBuf>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    create_decks: bool,
+    #[serde(default)]
+    subdecks_from_dirs: bool,
+    #[serde(default)]
+    path_tags: bool,
+    #[serde(default)]
+    notetypes: HashMap<String, String>,
+    #[serde(default)]
+    format: Format,
+    hook_protocol: Option<u8>,
+    /// In seconds
+    hook_timeout: Option<u64>,
+    /// Hooks turning other formats into .qz, by glob
+    #[serde(default)]
+    converters: BTreeMap<String, String>,
+    profile: Option<String>,
 }
 
 pub struct AllConfiguration {
     config_dir: PathBuf,
     pub anki_dir: PathBuf,
+    /// The Anki profile to sync, by default the one `anki_dir` belongs to
+    pub profile: String,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    pub create_decks: bool,
+    subdecks_from_dirs: bool,
+    path_tags: bool,
+    /// Notetype names by the alias used for `type` in the frontmatter
+    notetypes: HashMap<String, String>,
+    /// Of notes that don't set one in their frontmatter
+    pub format: Format,
+    hooks: Hooks,
 }
 
-pub fn get_config() -> Result<AllConfiguration, &'static str> {
-    let config_dir = search_for_config();
-    if config_dir.is_none() {
-        return Err("Not an anc directory. Initialize first.");
-    }
+#[derive(Default, Clone, Copy)]
+pub struct SaveOptions {
+    /// Roll everything back at the end
+    pub dry_run: bool,
+    /// Create decks that don't exist yet instead of failing
+    pub create_decks: bool,
+    /// Overwrite notes edited in Anki with their file, even if that's a conflict
+    pub force: bool,
+    /// Whether path tags are anc's, so that they are removed along with their file
+    pub path_tags: bool,
+}
 
-    let anki_dir = fs::read_to_string(config_dir.as_ref().unwrap().join("config"))
-        .map_or(None, |c| {
-            let config: Config = toml::from_str(&c).unwrap();
-            config.anki_dir
-        })
+pub fn get_config() -> Result<AllConfiguration> {
+    let config_dir = search_for_config()
+        .ok_or(Error::Config("Not an anc directory. Initialize first.".to_string()))?;
+
+    let config: Config = match fs::read_to_string(config_dir.join("config")) {
+        Err(_) => Config::default(),
+        Ok(c) => toml::from_str(&c).map_err(|e| Error::Config(format!("Could not parse .anc/config: {}", e)))?,
+    };
+
+    let include = if config.include.is_empty() {
+        None
+    } else {
+        Some(build_globs(&config.include)?)
+    };
+    let exclude = build_globs(&config.exclude)?;
+
+    let anki_dir = config.anki_dir
         .or({
             env::var("ANKI_DIR") 
                 .map_or(None, |ad| Some(PathBuf::from(ad)))
         })
-        .expect("Set anki_dir in .anc/config or set $ANKI_DIR");
+        .ok_or(Error::Config("Set anki_dir in .anc/config or set $ANKI_DIR".to_string()))?;
+
+    let hooks = Hooks::new(
+        &config_dir,
+        Protocol::from_version(config.hook_protocol.unwrap_or(1))?,
+        config.hook_timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+        Converters::new(&config_dir, config.converters)?,
+    );
+
+    let profile = config.profile
+        .or_else(|| anki_dir.file_name().map(|n| n.to_string_lossy().to_string()))
+        .ok_or_else(|| Error::Config("Set profile in .anc/config, anki_dir has no folder name".to_string()))?;
 
     Ok(AllConfiguration {
-        config_dir: config_dir.unwrap(),
+        config_dir,
         anki_dir,
+        profile,
+        include,
+        exclude,
+        create_decks: config.create_decks,
+        subdecks_from_dirs: config.subdecks_from_dirs,
+        path_tags: config.path_tags,
+        notetypes: config.notetypes.into_iter()
+            .map(|(alias, name)| (alias.to_lowercase(), name))
+            .collect(),
+        format: config.format,
+        hooks,
     })
 }
 
+fn build_globs(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for g in globs {
+        builder.add(Glob::new(g).map_err(|e| Error::Config(format!("Invalid glob in .anc/config: {}", e)))?);
+    }
+    builder.build().map_err(|e| Error::Config(format!("Invalid glob in .anc/config: {}", e)))
+}
+
 fn search_for_config() -> Option<PathBuf> {
-    find_config(Path::new(".").to_path_buf().canonicalize().unwrap())
+    find_config(Path::new(".").to_path_buf().canonicalize().ok()?)
 }
 
 fn find_config(mut path: PathBuf) -> Option<PathBuf> {
@@ -87,250 +233,549 @@ fn find_config(mut path: PathBuf) -> Option<PathBuf> {
     }
 }
 
-pub fn run() -> Result<Vec<(String, i32, i32)>, Vec<String>> {
-    let config = get_config().map_err(|e| vec![e.to_string()])?;
+/// Saves the files that changed since the last save, or every file with `full`
+///
+/// With `force`, files win over edits made in Anki, which resolves conflicts.
+pub fn run(full: bool, create_decks: bool, force: bool) -> Result<Vec<DeckReport>> {
+    let config = get_config()?;
+    let mut state = SyncState::load(&config.config_dir)?;
+    let mut cache = FileCache::load(&config.config_dir)?;
 
-    let paths = find_files(&config.config_dir, "qz");
+    let paths = find_files(&config);
+    cache.retain(&paths);
+    let mut settings = Settings::new(&config.config_dir, &config.hooks);
+    let to_save = if full { paths } else { cache.changed(&paths, &mut settings) };
+    if to_save.is_empty() {
+        return Ok(vec![]);
+    }
 
-    let cards = match parse_files(config.config_dir, paths) {
-        Err(errors) => {
-            for p in errors {
-                eprintln!("{}", p);
-            }
-            std::process::exit(65);
-        },
-        Ok(c) => c,
-    };
+    let cards = load_cards(&config, to_save.clone())?;
+    config.hooks.pre_save(&cards)?;
 
     // add/update from collection
-    process_cards(config.anki_dir.join("collection.anki2"), cards)
+    let options = SaveOptions {
+        dry_run: false,
+        create_decks: create_decks || config.create_decks,
+        force,
+        path_tags: config.path_tags,
+    };
+    let reports = save_cards(config.anki_dir.join("collection.anki2"), cards, options, Some(&mut state))?;
+    state.save(&config.config_dir)?;
+    cache.record(&to_save, &reports, &mut settings);
+    cache.save(&config.config_dir)?;
+    config.hooks.post_save(&reports)?;
+    Ok(reports)
 }
 
-fn find_files(config_dir: &PathBuf, extension: &str) -> Vec<PathBuf> {
-    let base_dir = config_dir.parent().unwrap().to_path_buf();
-    let mut to_check = vec![base_dir];
-    let mut paths = vec![];
-    // TODO: use .gitignore
-    while let Some(dir) = to_check.pop() {
-        for pr in fs::read_dir(dir).unwrap().into_iter() {
-            if let Ok(p) = pr {
-                let canon = p.path().canonicalize().unwrap();
-                if canon.is_dir() && canon.file_name().map(|e| e.to_str()).flatten() != Some(".anc") {
-                    to_check.push(p.path());
-                } else if canon.is_file() && canon.extension().map(|e| e.to_str()).flatten() == Some(extension) {
-                    paths.push(canon);
-                }
+/// Same as `run`, but every change is rolled back so the collection is left untouched
+pub fn status(create_decks: bool) -> Result<Vec<DeckReport>> {
+    let config = get_config()?;
+    let mut state = SyncState::load(&config.config_dir)?;
+
+    let cards = collect_cards(&config)?;
+
+    let options = SaveOptions {
+        dry_run: true,
+        create_decks: create_decks || config.create_decks,
+        force: false,
+        path_tags: config.path_tags,
+    };
+    save_cards(config.anki_dir.join("collection.anki2"), cards, options, Some(&mut state))
+}
+
+pub(crate) fn collect_cards(config: &AllConfiguration) -> Result<Vec<Deck>> {
+    let paths = find_files(config);
+
+    load_cards(config, paths)
+}
+
+/// Parses `paths` and applies the project configuration to the resulting decks
+pub(crate) fn load_cards(config: &AllConfiguration, paths: Vec<PathBuf>) -> Result<Vec<Deck>> {
+    let mut decks = parse_files(config.config_dir.clone(), paths, config.format, &config.hooks)?;
+    resolve_media(&mut decks, &config.anki_dir.join("collection.media"))?;
+    for g in decks.iter_mut().flat_map(|d| d.groups.iter_mut()) {
+        if let Some(name) = config.notetypes.get(&g.model.to_lowercase()) {
+            g.model = name.clone();
+            for c in g.cards.iter_mut() {
+                c.model = name.clone();
             }
         }
     }
-    paths
+    if config.path_tags {
+        add_path_tags(&mut decks, config.config_dir.parent().unwrap());
+    }
+    if config.subdecks_from_dirs {
+        decks = nest_decks(decks, config.config_dir.parent().unwrap());
+    }
+    config.hooks.post_parse(decks)
+}
+
+/// Moves each card into a subdeck of its deck named after the directories between `base_dir` and its file
+fn nest_decks(decks: Vec<Deck>, base_dir: &Path) -> Vec<Deck> {
+    let cards: Vec<(String, Card)> = decks.into_iter()
+        .flat_map(|d| {
+            let name = d.name;
+            d.groups.into_iter()
+                .flat_map(|g| g.cards)
+                .map(move |c| (name.clone(), c))
+        })
+        .map(|(name, card)| {
+            let (file, _) = split_id(&card.fields[0]);
+            let subdeck = Path::new(file).strip_prefix(base_dir).ok()
+                .and_then(|p| p.parent())
+                .map(|p| p.iter().map(|c| c.to_string_lossy()).join("::"))
+                .unwrap_or_default();
+            if subdeck.is_empty() {
+                (name, card)
+            } else {
+                (format!("{}::{}", name, subdeck), card)
+            }
+        })
+        .collect();
+    group_cards(cards)
+}
+
+/// Tags every card with the path of its file, e.g. `anc::path::biology::cells` for `biology/cells.qz`
+fn add_path_tags(decks: &mut [Deck], base_dir: &Path) {
+    for card in decks.iter_mut().flat_map(|d| d.groups.iter_mut()).flat_map(|g| g.cards.iter_mut()) {
+        let (file, _) = split_id(&card.fields[0]);
+        let relative = match Path::new(file).strip_prefix(base_dir) {
+            Ok(r) => r.with_extension(""),
+            Err(_) => continue,
+        };
+        let tag = format!(
+            "{}::{}",
+            PATH_TAG_PREFIX,
+            relative.iter().map(|c| c.to_string_lossy().split_whitespace().join("_")).join("::"),
+        );
+        card.tags = Some(match card.tags.take() {
+            Some(tags) => format!("{} {}", tags, tag),
+            None => tag,
+        });
+    }
+}
+
+/// Every .qz file below the directory containing `.anc`, and every file a converter is registered for
+///
+/// Files ignored by .gitignore, .git/info/exclude or .ancignore are skipped, as are the ones not matching the
+/// `include` and `exclude` globs of .anc/config, which are relative to that directory.
+pub(crate) fn find_files(config: &AllConfiguration) -> Vec<PathBuf> {
+    let base_dir = config.config_dir.parent().unwrap().to_path_buf();
+    WalkBuilder::new(&base_dir)
+        .hidden(false)
+        .follow_links(true)
+        .require_git(false)
+        .add_custom_ignore_filename(".ancignore")
+        .filter_entry(|e| e.file_name() != ".anc" && e.file_name() != ".git")
+        .build()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .map(|e| e.into_path())
+        .filter(|p| p.extension().is_some_and(|e| e == QZ_EXTENSION) || config.hooks.converts(p))
+        .filter(|p| {
+            let relative = p.strip_prefix(&base_dir).unwrap();
+            config.include.as_ref().is_none_or(|i| i.is_match(relative)) && !config.exclude.is_match(relative)
+        })
+        .filter_map(|p| p.canonicalize().ok())
+        .collect()
+}
+
+pub fn process_cards(path: PathBuf, decks: Vec<Deck>) -> Result<Vec<(String, i32, i32)>> {
+    save_cards(path, decks, SaveOptions::default(), None)
+        .map(|reports| {
+            reports.into_iter()
+                .map(|r| {
+                    let added = r.count(NoteChange::Added);
+                    let updated = r.count(NoteChange::Updated);
+                    (r.name, added, updated)
+                })
+                .collect()
+        })
 }
 
 // TODO:
 // - Check for duplicates
-pub fn process_cards(path: PathBuf, decks: Vec<Deck>) -> Result<Vec<(String, i32, i32)>, Vec<String>> {
-    let mut note_ids: Vec<NoteId> = vec![];
-    let mut deck_logs: Vec<Result<(String, i32, i32), String>> = Vec::with_capacity(decks.len());
-    let mut collection = CollectionBuilder::new(path).build().unwrap();
+/// Adds and updates notes from the parsed decks
+///
+/// With a `state`, notes only edited in Anki since the last save are written back to their files instead of being
+/// overwritten, and notes edited on both sides are left alone as conflicts.
+pub fn save_cards(path: PathBuf, decks: Vec<Deck>, options: SaveOptions, mut state: Option<&mut SyncState>) -> Result<Vec<DeckReport>> {
+    let mut context = SaveContext::default();
+    let mut deck_logs: Vec<Result<DeckReport>> = Vec::with_capacity(decks.len());
+    let mut collection = CollectionBuilder::new(path.clone()).build()?;
     {
-        collection.storage.db.prepare("savepoint anc").unwrap().execute([]).unwrap();
+        collection.storage.db.prepare("savepoint anc")?.execute([])?;
     }
+    let media: Vec<(PathBuf, String)> = decks.iter()
+        .flat_map(|d| d.groups.iter())
+        .flat_map(|g| g.cards.iter())
+        .flat_map(|c| c.media.iter().cloned())
+        .collect();
+    let file_tags: HashMap<String, Vec<String>> = decks.iter()
+        .flat_map(|d| d.groups.iter())
+        .flat_map(|g| g.cards.iter())
+        .map(|c| (c.fields[0].clone(), c.tag_list()))
+        .collect();
     for d in decks {
-        let (mut total_added, mut total_updated) = (0, 0);
-        let mut errors = vec![];
-        for g in d.groups {
-            let deck_id: i64;
-            let config_id: i64;
-            {
-                let mut type_ids = HashMap::new();
-                let mut type_query = collection.storage.db.prepare(
-                    "
-                        SELECT nt.id, count(*)
-                        FROM notetypes nt
-                        join fields fd on fd.ntid = nt.id
-                        WHERE nt.name like ?
-                    ").unwrap();
-
-                let mut existing_note = collection.storage.db.prepare(
-                    "
-                        SELECT id, flds, tags
-                        FROM notes
-                        WHERE SUBSTR(flds, 0, INSTR(flds, char(31))) like ?
-                        limit 1
-                    ").unwrap();
-                let mut check_time = collection.storage.db.prepare("SELECT ifnull(max(id), 0) FROM notes").unwrap();
-                // TODO: try named parameters instead
-                let mut insert_note = collection.storage.db.prepare("insert or replace into notes values (?, ?, ?, ?, ?, ?, ?, ?, 0, 0, '')").unwrap();
-                let mut update_note = collection.storage.db.prepare(
-                    "update notes set mod = ?, usn = ?, tags = ?, flds = ?, sfld = ?
-                     where id = ?"
-                ).unwrap();
-                let mut get_deck = collection.storage.db.prepare("select id from decks where name like ?").unwrap();
-                let mut get_deck_kind = collection.storage.db.prepare("select kind from decks where id = ?").unwrap();
-                let mut set_config = collection.storage.db.prepare("insert or replace into config (key, usn, mtime_secs, val) values (?, ?, ?, ?)").unwrap();
-
-                let (type_id, field_count) = if let Some(&(id, amount)) = type_ids.get(&g.model) {
-                    (id, amount)
-                } else {
-                    let (id, amount) = type_query.query(params![g.model])
-                        .unwrap()
-                        .mapped(|row| Ok((
-                            row.get::<usize, i64>(0).expect("Can't find card model"),
-                            row.get::<usize, usize>(1).unwrap(),
-                        )))
-                        .next()
-                        .unwrap()
-                        .expect("Can't find card model");
-                    type_ids.insert(g.model, (id, amount));
-                    (id, amount)
-                };
-
-                // split into adds and updates
-                let (to_add, to_update): (Vec<_>, Vec<_>) = g.cards
-                    .iter()
-                    .partition_map(|card| {
-                        if let Ok(Some(row)) = existing_note.query(params![card.fields.get(0).unwrap()]).unwrap().next() {
-                            let note_id: i64 = row.get(0).unwrap();
-                            let flds: String = row.get(1).unwrap();
-                            let tags: String = row.get(2).unwrap();
-                            Either::Right((
-                                note_id,
-                                flds,
-                                tags,
-                                card
-                            ))
-                        } else {
-                            Either::Left(card)
-                        }
-                    });
-                // note id
-                let current = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
-                let mut next_note_id = if let Some(row) = check_time.query([]).unwrap().next().unwrap() {
-                    let max: i64 = row.get(0).unwrap();
-                    if max > current {
-                        max + 1
-                    } else {
-                        current
-                    }
-                } else {
-                    current
-                };
-                let usn: i64 = -1; // not a server
-                // add new
-                let mut encode_buffer = Uuid::encode_buffer();
-                for n in to_add {
-                    // map to field string, nothing else is used
-                    let fieldstr = build_field_str(&n.fields, field_count, n.fields.len());
-                    let uuid: &str = Uuid::new_v4().to_simple().encode_lower(&mut encode_buffer);
-                    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as i64;
-
-                    let added_count = insert_note.execute(params![
-                        next_note_id,
-                        uuid,
-                        type_id,
-                        time,
-                        usn,
-                        n.tags.as_ref().map(|t| format!(" {} ", t.trim())).unwrap_or(" ".to_string()),
-                        fieldstr.as_str(),
-                        n.fields.get(0).unwrap().as_str(),
-                    ]).unwrap();
-                    
-                    // has to be either 0 or one
-                    if added_count > 0 {
-                        note_ids.push(NoteId::from(next_note_id));
-                        total_added += 1;
+        let mut report = DeckReport::new(d.name.clone());
+        if !deck_exists(&collection, &d.name)? {
+            if !options.create_decks {
+                deck_logs.push(Err(Error::MissingDeck(d.name)));
+                continue;
+            }
+            if let Err(e) = create_deck(&mut collection, &d.name) {
+                deck_logs.push(Err(e));
+                continue;
+            }
+            report.created = true;
+        }
+        let result = save_deck(&mut collection, &d, &mut report, &mut context, state.as_deref(), options);
+        deck_logs.push(result.map(|_| report));
+    }
+    if deck_logs.iter().all(|l| l.is_ok()) {
+        if options.dry_run {
+            collection.storage.db.prepare("rollback to anc")?.execute([])?;
+        } else {
+            // files and collection change together, so a failure here doesn't leave notes looking edited on both sides
+            let originals: HashMap<&str, Option<String>> = context.write_backs.iter()
+                .map(|(id, _, _)| split_id(id).0)
+                .map(|file| (file, fs::read_to_string(file).ok()))
+                .collect();
+            let written = context.write_backs.iter()
+                .try_for_each(|(id, flds, format)| write_back(id, flds, *format))
+                .and_then(|_| add_media(&path, &media));
+            if let Err(e) = written {
+                for (file, text) in originals {
+                    if let Some(text) = text {
+                        let _ = fs::write(file, text);
                     }
+                }
+                collection.storage.db.prepare("rollback to anc")?.execute([])?;
+                collection.storage.db.prepare("release anc")?.execute([])?;
+                return Err(e);
+            }
+        }
+        collection.storage.db.prepare("release anc")?.execute([])?; // commit
+        let reports: Vec<DeckReport> = deck_logs.into_iter()
+            .map(|l| l.unwrap())
+            .collect();
 
-                    next_note_id += 1;
+        if let (Some(state), false) = (state.as_mut(), options.dry_run) {
+            let mut saved_note = collection.storage.db.prepare(
+                "
+                    SELECT flds, mod
+                    FROM notes
+                    WHERE SUBSTR(flds, 0, INSTR(flds, char(31))) like ?
+                    limit 1
+                ")?;
+            for (id, change) in reports.iter().flat_map(|r| r.notes.iter()).filter(|(_, c)| *c != NoteChange::Conflict) {
+                if let Ok((flds, modified)) = saved_note.query_row(params![id], |row| Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, i64>(1)?,
+                ))) {
+                    // written back notes keep their tags in Anki until the next save
+                    let tags = match change {
+                        NoteChange::WrittenBack => state.owned_tags(id).map(|t| t.to_vec()),
+                        _ => Some(file_tags.get(id).cloned().unwrap_or_default()),
+                    };
+                    state.record(id.clone(), &flds, modified, tags);
                 }
+            }
+        }
+        Ok(reports)
+    } else {
+        Err(Error::from_many(
+            deck_logs.into_iter()
+                .filter_map(|l| l.err())
+                .collect()
+        ))
+    }
+}
 
-                // add updates
-                for (note_id, existing_flds, existing_tags, n) in to_update {
-                    let fieldstr = build_field_str(&n.fields, field_count, n.fields.len());
-                    let tags = n.tags.as_ref().map(|t| format!(" {} ", t.trim())).unwrap_or(" ".to_string());
-
-                    if fieldstr == existing_flds && tags == existing_tags { break }
-
-                    let first_field = n.fields.get(0).unwrap().clone();
-                    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as i64;
-
-                    let changed_count = update_note.execute(params![
-                        time,
-                        usn,
-                        tags,
-                        fieldstr,
-                        first_field.as_str(),
-                        note_id,
-                    ]).unwrap();
-                    
-                    // has to be either 0 or one
-                    if changed_count > 0 {
-                        note_ids.push(NoteId::from(note_id));
-                        total_updated += 1;
-                    }
+/// Which side wins for the note of `card`, see `state::resolve`, along with Anki's version as it would be written back
+pub(crate) fn resolve_note(card: &Card, file_flds: &str, anki_flds: &str, anki_modified: i64, state: Option<&SyncState>, force: bool) -> (Resolution, String) {
+    let id = &card.fields[0];
+    let in_file = restore_references(anki_flds, id, &card.media);
+    // files with a converter aren't .qz
+    let writable = parsing::can_write_back(&in_file, card.format)
+        && Path::new(split_id(id).0).extension().is_some_and(|e| e == QZ_EXTENSION);
+    let saved = state.and_then(|s| s.get(id));
+    (state::resolve(saved, file_flds, anki_flds, anki_modified, writable, force), in_file)
+}
+
+/// What `save_deck` collects across the decks of one save
+#[derive(Default)]
+struct SaveContext {
+    type_ids: HashMap<String, (i64, Vec<String>)>, // field names in order
+    note_ids: Vec<NoteId>,
+    /// Notes to write back once every deck is saved
+    write_backs: Vec<(String, String, Format)>, // (id, flds, format of the note)
+}
+
+/// Adds and updates the notes of one deck, recording what happened in `report`
+fn save_deck(
+    collection: &mut Collection,
+    d: &Deck,
+    report: &mut DeckReport,
+    context: &mut SaveContext,
+    state: Option<&SyncState>,
+    options: SaveOptions,
+) -> Result<()> {
+    let SaveOptions { force, path_tags, .. } = options;
+    let SaveContext { type_ids, note_ids, write_backs } = context;
+    for g in &d.groups {
+        let deck_id: i64;
+        let config_id: i64;
+        {
+            let mut type_query = collection.storage.db.prepare("SELECT id FROM notetypes WHERE name like ?")?;
+            let mut field_names = collection.storage.db.prepare("SELECT name FROM fields WHERE ntid = ? ORDER BY ord")?;
+
+            let mut existing_note = collection.storage.db.prepare(
+                "
+                    SELECT id, flds, tags, mod
+                    FROM notes
+                    WHERE SUBSTR(flds, 0, INSTR(flds, char(31))) like ?
+                    limit 1
+                ")?;
+            let mut check_time = collection.storage.db.prepare("SELECT ifnull(max(id), 0) FROM notes")?;
+            // TODO: try named parameters instead
+            let mut insert_note = collection.storage.db.prepare("insert or replace into notes values (?, ?, ?, ?, ?, ?, ?, ?, 0, 0, '')")?;
+            let mut update_note = collection.storage.db.prepare(
+                "update notes set mod = ?, usn = ?, tags = ?, flds = ?, sfld = ?
+                 where id = ?"
+            )?;
+            let mut get_deck = collection.storage.db.prepare("select id from decks where name like ?")?;
+            let mut get_deck_kind = collection.storage.db.prepare("select kind from decks where id = ?")?;
+            let mut set_config = collection.storage.db.prepare("insert or replace into config (key, usn, mtime_secs, val) values (?, ?, ?, ?)")?;
+
+            if !type_ids.contains_key(&g.model) {
+                let id: i64 = type_query.query_row(params![g.model], |row| row.get(0))
+                    .optional()?
+                    .ok_or_else(|| Error::MissingNotetype(g.model.clone()))?;
+                let names = field_names.query_map(params![id], |row| row.get(0))?
+                    .collect::<std::result::Result<Vec<String>, _>>()?;
+                type_ids.insert(g.model.clone(), (id, names));
+            }
+            let (type_id, names) = type_ids.get(&g.model).unwrap();
+            let (type_id, field_count) = (*type_id, names.len());
+
+            // split into adds and updates
+            let mut to_add = vec![];
+            let mut to_update = vec![];
+            for card in &g.cards {
+                let fields = card.resolve_fields(names)?;
+                let existing = existing_note.query_row(params![card.fields.get(0).unwrap()], |row| Ok((
+                        row.get::<usize, i64>(0)?,
+                        row.get::<usize, String>(1)?,
+                        row.get::<usize, String>(2)?,
+                        row.get::<usize, i64>(3)?,
+                    )))
+                    .optional()?;
+                match existing {
+                    Some((note_id, flds, tags, modified)) => to_update.push((note_id, flds, tags, modified, card, fields)),
+                    None => to_add.push((card, fields)),
                 }
-                // these config values are used by after_note_updates
-                deck_id = get_deck.query(params![d.name]).unwrap().next()
-                    .expect(&format!("Deck {} does not exist", d.name))
-                    .expect(&format!("Deck {} does not exist", d.name))
-                    .get(0).unwrap();
-                set_config.execute(params![
-                    format!("_nt_{0}_lastDeck", type_id),
-                    usn,
-                    TimestampSecs::now(),
-                    serde_json::to_vec(&deck_id).unwrap(),
-                ]).unwrap();
-                let kind_blob: Vec<u8> = get_deck_kind.query(params![deck_id]).unwrap().next()
-                    .unwrap()
-                    .unwrap()
-                    .get(0)
-                    .unwrap();
-                let kind = DeckKindContainer::decode(kind_blob.as_slice()).unwrap();
-                config_id = if let Some(DeckKind::Normal(ref normal)) = kind.kind {
-                    Some(normal.config_id)
-                } else {
-                    None
-                }.unwrap();
             }
-            // create cards
-            let result = collection.after_note_updates(&*note_ids, true, false);
-            if result.is_err() {
-                errors.push(result.unwrap_err());
+            // note id
+            let current = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+            let max: i64 = check_time.query_row([], |row| row.get(0))?;
+            let mut next_note_id = if max > current {
+                max + 1
             } else {
-                let config = collection.get_deck_config(DeckConfigId::from(config_id), true).unwrap().unwrap();
-                if config.inner.new_card_insert_order == NewCardInsertOrder::Random as i32 {
-                    collection.sort_deck_legacy(DeckId::from(deck_id), true).unwrap();
+                current
+            };
+            let usn: i64 = -1; // not a server
+            // add new
+            let mut encode_buffer = Uuid::encode_buffer();
+            for (n, fields) in to_add {
+                // map to field string, nothing else is used
+                let fieldstr = build_field_str(&fields, field_count, fields.len());
+                let uuid: &str = Uuid::new_v4().to_simple().encode_lower(&mut encode_buffer);
+                let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as i64;
+
+                let added_count = insert_note.execute(params![
+                    next_note_id,
+                    uuid,
+                    type_id,
+                    time,
+                    usn,
+                    n.tags.as_ref().map(|t| format!(" {} ", t.trim())).unwrap_or(" ".to_string()),
+                    fieldstr.as_str(),
+                    n.fields.get(0).unwrap().as_str(),
+                ])?;
+
+                // has to be either 0 or one
+                if added_count > 0 {
+                    note_ids.push(NoteId::from(next_note_id));
+                    report.push(n.fields.get(0).unwrap().clone(), NoteChange::Added);
+                }
+
+                next_note_id += 1;
+            }
+
+            // add updates
+            for (note_id, existing_flds, existing_tags, existing_mod, n, fields) in to_update {
+                let fieldstr = build_field_str(&fields, field_count, fields.len());
+                let id = &n.fields[0];
+                let owned = state.and_then(|s| s.owned_tags(id));
+                let tags = state::merge_tags(&existing_tags, owned, &n.tag_list(), path_tags);
+
+                let (resolution, anki_flds) = resolve_note(n, &fieldstr, &existing_flds, existing_mod, state, force);
+                match resolution {
+                    Resolution::FromFile => {},
+                    Resolution::Conflict => {
+                        report.push(id.clone(), NoteChange::Conflict);
+                        continue;
+                    },
+                    Resolution::WriteBack => {
+                        write_backs.push((id.clone(), anki_flds, n.format));
+                        report.push(id.clone(), NoteChange::WrittenBack);
+                        continue;
+                    },
+                }
+
+                if fieldstr == existing_flds && state::same_tags(&existing_tags, &tags) {
+                    report.push(n.fields.get(0).unwrap().clone(), NoteChange::Unchanged);
+                    continue;
+                }
+
+                let first_field = n.fields.get(0).unwrap().clone();
+                let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as i64;
+
+                let changed_count = update_note.execute(params![
+                    time,
+                    usn,
+                    format!(" {} ", tags.join(" ")),
+                    fieldstr,
+                    first_field.as_str(),
+                    note_id,
+                ])?;
+
+                // has to be either 0 or one
+                if changed_count > 0 {
+                    note_ids.push(NoteId::from(note_id));
+                    report.push(first_field, NoteChange::Updated);
                 }
             }
+            // these config values are used by after_note_updates
+            deck_id = get_deck.query_row(params![native_deck_name(&d.name)], |row| row.get(0))
+                .optional()?
+                .ok_or_else(|| Error::MissingDeck(d.name.clone()))?;
+            set_config.execute(params![
+                format!("_nt_{0}_lastDeck", type_id),
+                usn,
+                TimestampSecs::now(),
+                serde_json::to_vec(&deck_id).unwrap(),
+            ])?;
+            let kind_blob: Vec<u8> = get_deck_kind.query_row(params![deck_id], |row| row.get(0))?;
+            let kind = DeckKindContainer::decode(kind_blob.as_slice())
+                .map_err(|e| Error::Collection(format!("Deck {} is corrupt: {}", d.name, e)))?;
+            config_id = if let Some(DeckKind::Normal(ref normal)) = kind.kind {
+                normal.config_id
+            } else {
+                return Err(Error::Collection(format!("Deck {} is a filtered deck", d.name)));
+            };
         }
-        // TODO: clean this up
-        if errors.is_empty() {
-            deck_logs.push(Ok((d.name, total_added, total_updated)));
-        } else {
-            deck_logs.push(Err(
-                errors.into_iter()
-                    .map(|e| e.localized_description(&collection.tr))
-                    .reduce(|mut output, e| {
-                        output += &e;
-                        output
-                    }).unwrap()
-            ));
+        // create cards
+        collection.after_note_updates(&*note_ids, true, false)
+            .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+        if let Some(config) = collection.get_deck_config(DeckConfigId::from(config_id), true)? {
+            if config.inner.new_card_insert_order == NewCardInsertOrder::Random as i32 {
+                collection.sort_deck_legacy(DeckId::from(deck_id), true)?;
+            }
         }
     }
-    if deck_logs.iter().all(|l| l.is_ok()) {
-        collection.storage.db.prepare("release anc").unwrap().execute([]).unwrap(); // commit
-        Ok(deck_logs.into_iter()
-            .map(|l| l.unwrap())
-            .collect())
-    } else {
-        Err(deck_logs.into_iter()
-            .filter(|l| l.is_err())
-            .map(|l| l.unwrap_err())
-            .collect())
+    Ok(())
+}
+
+/// Decks are stored with \x1f instead of :: between levels
+pub(crate) fn native_deck_name(name: &str) -> String {
+    name.replace("::", "\u{1f}")
+}
+
+fn deck_exists(collection: &Collection, name: &str) -> Result<bool> {
+    Ok(collection.storage.db.prepare("select 1 from decks where name like ?")?
+        .exists(params![native_deck_name(name)])?)
+}
+
+/// Adds the deck and any of its parents that are missing
+fn create_deck(collection: &mut Collection, name: &str) -> Result<()> {
+    let parts: Vec<&str> = name.split("::").collect();
+    for i in 1..=parts.len() {
+        let name = parts[..i].join("::");
+        if !deck_exists(collection, &name)? {
+            let mut deck = anki::decks::Deck::new_normal();
+            deck.name = NativeDeckName::from_human_name(&name);
+            collection.add_deck(&mut deck)
+                .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+        }
     }
+    Ok(())
 }
 
-fn build_field_str(fields: &Vec<String>, model_field_count: usize, fields_entered_count: usize) -> String {
+pub(crate) fn build_field_str(fields: &Vec<String>, model_field_count: usize, fields_entered_count: usize) -> String {
     let pad = model_field_count.checked_sub(fields_entered_count).unwrap_or(0);
     format!("{}{}", fields.join("\u{1f}"), "\u{1f}".repeat(pad))
 }
+
+#[test]
+fn ignored_files() {
+    let config_dir = Path::new("test_files/ignored/.anc").canonicalize().unwrap();
+    let config = AllConfiguration {
+        config_dir: config_dir.clone(),
+        anki_dir: PathBuf::new(),
+        profile: String::new(),
+        include: None,
+        exclude: build_globs(&["drafts/**".to_string()]).unwrap(),
+        create_decks: false,
+        subdecks_from_dirs: false,
+        path_tags: false,
+        notetypes: HashMap::new(),
+        format: Format::default(),
+        hooks: Hooks::new(&config_dir, Protocol::V1, DEFAULT_TIMEOUT, Converters::new(&config_dir, BTreeMap::new()).unwrap()),
+    };
+    let base_dir = config_dir.parent().unwrap();
+
+    let mut paths: Vec<_> = find_files(&config).into_iter()
+        .map(|p| p.strip_prefix(base_dir).unwrap().display().to_string())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, vec!["kept.qz", "nested/kept.qz"]);
+
+    let config = AllConfiguration {
+        include: Some(build_globs(&["nested/*.qz".to_string()]).unwrap()),
+        ..config
+    };
+    let paths: Vec<_> = find_files(&config).into_iter()
+        .map(|p| p.strip_prefix(base_dir).unwrap().display().to_string())
+        .collect();
+    assert_eq!(paths, vec!["nested/kept.qz"]);
+}
+
+#[test]
+fn path_tags() {
+    let mut decks = group_cards(vec![
+        ("example".to_string(), Card::new("basic".to_string(), vec!["/notes/top.qz#1".to_string()], None)),
+        ("example".to_string(), Card::new("basic".to_string(), vec!["/notes/biology/cell biology.qz#1".to_string()], Some("x".to_string()))),
+        ("example".to_string(), Card::new("basic".to_string(), vec!["elsewhere".to_string()], None)),
+        ("example".to_string(), Card::new("basic".to_string(), vec!["/notes/orphan.qz#1".to_string()], None)),
+    ]);
+
+    add_path_tags(&mut decks, Path::new("/notes"));
+    let tags: Vec<_> = decks[0].groups[0].cards.iter().map(|c| c.tags.as_deref()).collect();
+    assert_eq!(tags, vec![Some("anc::path::top"), Some("x anc::path::biology::cell_biology"), None, Some("anc::path::orphan")]);
+}
+
+#[test]
+fn nested_decks() {
+    let decks = group_cards(vec![
+        ("example".to_string(), Card::new("basic".to_string(), vec!["/notes/top.qz#1".to_string()], None)),
+        ("example".to_string(), Card::new("basic".to_string(), vec!["/notes/bio/cells/a.qz#1".to_string()], None)),
+        ("example".to_string(), Card::new("cloze".to_string(), vec!["/notes/bio/cells/a.qz#2".to_string()], None)),
+    ]);
+
+    let nested = nest_decks(decks, Path::new("/notes"));
+    let names: Vec<_> = nested.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["example", "example::bio::cells"]);
+    assert_eq!(nested[1].groups.len(), 2);
+}
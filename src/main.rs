@@ -1,8 +1,9 @@
-use std::process::exit;
+use std::{io::{self, Write}, path::PathBuf, process::exit};
 
 use clap::{Parser, Subcommand};
 
-use anc::{run, init, sync::sync};
+use anc::{Error, run, init, status, get_config, sync::{sync, profiles}, diff::diff, prune::{prune, Orphan, PruneAction}, pull::pull, watch::watch, notetypes, cards::{DeckReport, NoteChange, split_id}};
+use itertools::Itertools;
 use tokio::runtime::Runtime;
 
 #[derive(Parser)]
@@ -16,59 +17,232 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Update Anki with files in current Anc directory
-    Save { },
+    Save {
+        /// Afterwards, remove notes whose card was deleted from its file
+        #[clap(long, conflicts_with = "watch")]
+        prune: bool,
+        /// Keep running and save files whenever they change
+        #[clap(long)]
+        watch: bool,
+        /// Save every file, not just the ones changed since the last save
+        #[clap(long, conflicts_with = "watch")]
+        full: bool,
+        /// Create decks that don't exist yet
+        #[clap(long)]
+        create_decks: bool,
+        /// Overwrite notes edited in Anki with their files, resolving conflicts in favor of the files
+        #[clap(long, conflicts_with = "watch")]
+        force: bool,
+    },
+    /// Show what `save` would add and update without changing Anki
+    Status {
+        /// Assume decks that don't exist yet would be created
+        #[clap(long)]
+        create_decks: bool,
+    },
+    /// Show field-level differences between files and the notes in Anki
+    Diff { },
+    /// Remove notes whose card was deleted from its file
+    Prune {
+        /// Tag orphaned notes with anc::orphan instead of deleting them
+        #[clap(long)]
+        tag: bool,
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Write notes of a deck that were created in Anki into .qz files
+    Pull {
+        #[clap(long)]
+        deck: String,
+        /// Only pull notes of this notetype
+        #[clap(long)]
+        notetype: Option<String>,
+    },
     r#Sync { },
-    Init { },
+    /// List the Anki profiles, marking the one `sync` uses
+    Profiles { },
+    /// Create an anc configuration in the current directory
+    Init {
+        /// The Anki profile directory containing collection.anki2
+        #[clap(long)]
+        anki_dir: Option<PathBuf>,
+        /// Add copies of the stock notetypes with an Id field to the collection
+        #[clap(long)]
+        notetypes: bool,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
 
     let output = match &cli.command {
-        Commands::Save { } => {
-            run()
-                .map(|successes| {
-                    let added_length = successes.iter()
-                        .map(|(_, added, _)| added)
-                        .max()
-                        .map(|m| m.to_string().len());
-                    let updated_length = successes.iter()
-                        .map(|(_, _, updated)| updated)
-                        .max()
-                        .map(|m| m.to_string().len());
-                    let output: Vec<String> = successes.into_iter()
-                        .filter(|(_, added, updated)| *added != 0 || *updated != 0)
-                        .map(|(name, added, updated)| format!(
-                                "{added:apad$} added and {updated:upad$} updated to {name}",
-                                added=added,
-                                updated=updated,
-                                apad=added_length.unwrap(),
-                                upad=updated_length.unwrap()
-                        ))
+        Commands::Save { watch: true, create_decks, .. } => {
+            notetypes::apply()
+                .and_then(|output| {
+                    if !output.is_empty() {
+                        eprintln!("{}", output.join("\n"));
+                    }
+                    watch(*create_decks, |result| match result {
+                        Ok(reports) => eprintln!("{}", save_summary(reports).join("\n")),
+                        Err(e) => eprintln!("{}", e),
+                    })
+                })
+                .map(|_| vec![])
+        },
+        Commands::Save { prune: should_prune, full, create_decks, force, .. } => {
+            let saved = notetypes::apply()
+                .and_then(|mut output| {
+                    output.append(&mut save_summary(run(*full, *create_decks, *force)?));
+                    Ok(output)
+                });
+            if *should_prune {
+                saved.and_then(|mut output| {
+                    output.append(&mut prune(PruneAction::Delete, |o| confirm_prune(o, "Delete"))?);
+                    Ok(output)
+                })
+            } else {
+                saved
+            }
+        },
+        Commands::Prune { tag, yes } => {
+            let (action, verb) = if *tag { (PruneAction::Tag, "Tag") } else { (PruneAction::Delete, "Delete") };
+            prune(action, |o| *yes || confirm_prune(o, verb))
+        },
+        Commands::Status { create_decks } => {
+            status(*create_decks)
+                .map(|reports| {
+                    let output: Vec<String> = reports.iter()
+                        .flat_map(status_report)
                         .collect();
                     if output.is_empty() {
-                        vec!["Nothing was added or updated".to_string()]
+                        vec!["No notes found".to_string()]
                     } else {
                         output
                     }
                 })
         },
+        Commands::Diff { } => {
+            diff()
+                .map(|diffs| {
+                    if diffs.is_empty() {
+                        vec!["No differences".to_string()]
+                    } else {
+                        diffs.iter()
+                            .flat_map(|d| d.unified())
+                            .collect()
+                    }
+                })
+        },
+        Commands::Pull { deck, notetype } => {
+            pull(deck, notetype.as_deref())
+        },
         Commands::Sync { } => {
             let result = sync();
             let runtime = Runtime::new().unwrap();
             runtime.block_on(result)
         }
-        Commands::Init { } => {
-            init()
+        Commands::Profiles { } => {
+            get_config()
+                .and_then(|config| Ok(
+                    profiles(&config.anki_dir)?.into_iter()
+                        .map(|p| if p == config.profile { format!("* {}", p) } else { format!("  {}", p) })
+                        .collect()
+                ))
+        },
+        Commands::Init { anki_dir, notetypes } => {
+            init(anki_dir.clone(), *notetypes)
         },
     };
     match output {
         Err(e) => {
-            eprintln!("{}", e.concat());
-            exit(1);
+            eprintln!("{}", e);
+            // like EX_DATAERR, for files that can't be parsed
+            let parse_error = e.errors().iter().any(|e| matches!(e, Error::Parse { .. }));
+            exit(if parse_error { 65 } else { 1 });
         },
         Ok(successes) => {
-            eprintln!("{}", successes.concat());
+            eprintln!("{}", successes.join("\n"));
         }
     }
 }
+
+fn save_summary(reports: Vec<DeckReport>) -> Vec<String> {
+    let successes: Vec<_> = reports.iter()
+        .map(|r| (&r.name, r.count(NoteChange::Added), r.count(NoteChange::Updated)))
+        .collect();
+    let added_length = successes.iter()
+        .map(|(_, added, _)| added)
+        .max()
+        .map(|m| m.to_string().len());
+    let updated_length = successes.iter()
+        .map(|(_, _, updated)| updated)
+        .max()
+        .map(|m| m.to_string().len());
+    let mut output: Vec<String> = successes.into_iter()
+        .filter(|(_, added, updated)| *added != 0 || *updated != 0)
+        .map(|(name, added, updated)| format!(
+                "{added:apad$} added and {updated:upad$} updated to {name}",
+                added=added,
+                updated=updated,
+                apad=added_length.unwrap(),
+                upad=updated_length.unwrap()
+        ))
+        .collect();
+    for r in reports.iter().filter(|r| r.created) {
+        output.insert(0, format!("Created deck {}", r.name));
+    }
+    for (id, change) in reports.iter().flat_map(|r| r.notes.iter()) {
+        match change {
+            NoteChange::WrittenBack => output.push(format!("{} was edited in Anki and written back", id)),
+            NoteChange::Conflict => output.push(format!("{} was edited both in Anki and in its file, skipped", id)),
+            _ => {},
+        }
+    }
+    if output.is_empty() {
+        vec!["Nothing was added or updated".to_string()]
+    } else {
+        output
+    }
+}
+
+fn status_report(report: &DeckReport) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{}: {} to add, {} to update, {} unchanged, {} to write back, {} conflicting",
+        report.name,
+        report.count(NoteChange::Added),
+        report.count(NoteChange::Updated),
+        report.count(NoteChange::Unchanged),
+        report.count(NoteChange::WrittenBack),
+        report.count(NoteChange::Conflict),
+    )];
+    let files = report.notes.iter()
+        .map(|(id, change)| (split_id(id), change))
+        .into_group_map_by(|((file, _), _)| file.to_string());
+    for (file, notes) in files.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        lines.push(format!("  {}", file));
+        for ((_, index), change) in notes {
+            let label = match change {
+                NoteChange::Added => "add",
+                NoteChange::Updated => "update",
+                NoteChange::Unchanged => "keep",
+                NoteChange::WrittenBack => "pull",
+                NoteChange::Conflict => "conflict",
+            };
+            lines.push(format!("    {:<8} #{}", label, index.unwrap_or("-")));
+        }
+    }
+    lines
+}
+
+fn confirm_prune(orphans: &[Orphan], verb: &str) -> bool {
+    eprintln!("Orphaned notes:");
+    for o in orphans {
+        eprintln!("  {} ({})", o.id, o.model);
+    }
+    eprint!("{} {} notes? [y/N] ", verb, orphans.len());
+    io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
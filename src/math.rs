@@ -0,0 +1,114 @@
+/// A piece of a field that is either text or math, which has to reach Anki untouched
+#[derive(Debug, PartialEq)]
+pub enum Span {
+    Text(String),
+    Math {
+        display: bool,
+        tex: String,
+    },
+}
+
+impl Span {
+    /// Math with the delimiters Anki's MathJax looks for, text as it is
+    pub fn to_anki(&self) -> String {
+        match self {
+            Span::Text(text) => text.clone(),
+            Span::Math { display: false, tex } => format!("\\({}\\)", tex),
+            Span::Math { display: true, tex } => format!("\\[{}\\]", tex),
+        }
+    }
+}
+
+/// Splits out `$...$`, `$$...$$`, `\(...\)` and `\[...\]`
+///
+/// Like in pandoc, a `$` only starts inline math if it's followed by a non-space, and only ends it if it follows a
+/// non-space and isn't followed by a digit, so prices stay text. `\$` is a literal `$`. With `skip_code`, text between
+/// backticks is left alone.
+pub fn split(text: &str, skip_code: bool) -> Vec<Span> {
+    let mut spans = vec![];
+    let mut current = String::new();
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if skip_code && rest.starts_with('`') {
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            let end = rest[ticks..].find(&rest[..ticks])
+                .map_or(rest.len(), |e| e + 2 * ticks);
+            current += &rest[..end];
+            i += end;
+        } else if rest.starts_with("\\$") {
+            current.push('$');
+            i += 2;
+        } else if let Some((display, tex, length)) = math_at(rest) {
+            if !current.is_empty() {
+                spans.push(Span::Text(std::mem::take(&mut current)));
+            }
+            spans.push(Span::Math {
+                display,
+                tex: tex.to_string(),
+            });
+            i += length;
+        } else {
+            let c = rest.chars().next().unwrap();
+            current.push(c);
+            i += c.len_utf8();
+        }
+    }
+    if !current.is_empty() {
+        spans.push(Span::Text(current));
+    }
+    spans
+}
+
+/// The math starting at the beginning of `text`: (display, tex, length including delimiters)
+fn math_at(text: &str) -> Option<(bool, &str, usize)> {
+    for (open, close, display) in [("\\(", "\\)", false), ("\\[", "\\]", true), ("$$", "$$", true)] {
+        if let Some(rest) = text.strip_prefix(open) {
+            let end = rest.find(close)?;
+            return Some((display, &rest[..end], open.len() + end + close.len()));
+        }
+    }
+
+    let rest = text.strip_prefix('$')?;
+    if rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut search = 0;
+    loop {
+        let end = search + rest[search..].find('$')?;
+        let tex = &rest[..end];
+        if tex.contains("\n\n") {
+            return None;
+        }
+        let closes = !tex.is_empty()
+            && !tex.ends_with(char::is_whitespace)
+            && !tex.ends_with('\\')
+            && !rest[end + 1..].starts_with(|c: char| c.is_ascii_digit());
+        if closes {
+            return Some((false, tex, end + 2));
+        }
+        search = end + 1;
+    }
+}
+
+#[test]
+fn delimiters() {
+    assert_eq!(
+        split("If $a < b$ and \\(b < c\\), then $$a < c$$ or \\[a \\ne c\\]", false),
+        vec![
+            Span::Text("If ".to_string()),
+            Span::Math { display: false, tex: "a < b".to_string() },
+            Span::Text(" and ".to_string()),
+            Span::Math { display: false, tex: "b < c".to_string() },
+            Span::Text(", then ".to_string()),
+            Span::Math { display: true, tex: "a < c".to_string() },
+            Span::Text(" or ".to_string()),
+            Span::Math { display: true, tex: "a \\ne c".to_string() },
+        ]
+    );
+    assert_eq!(split("$5 and $10, \\$x\\$", false), vec![Span::Text("$5 and $10, $x$".to_string())]);
+    assert_eq!(split("`$x$` $y$", true), vec![
+        Span::Text("`$x$` ".to_string()),
+        Span::Math { display: false, tex: "y".to_string() },
+    ]);
+}
@@ -0,0 +1,191 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use anki::media::MediaManager;
+
+use crate::{Error, Result, cards::{Deck, split_id}};
+
+/// Points the media references of every card at content-hashed names in `media_dir`, recording the files to copy
+///
+/// References are resolved relative to the card's file. Ones that only exist in `media_dir` already, like those
+/// written back from Anki, and URLs are left alone.
+pub fn resolve_media(decks: &mut [Deck], media_dir: &Path) -> Result<()> {
+    let mut errors = vec![];
+    for card in decks.iter_mut().flat_map(|d| d.groups.iter_mut()).flat_map(|g| g.cards.iter_mut()) {
+        let id = card.fields[0].clone();
+        let base_dir = Path::new(split_id(&id).0).parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let mut media = vec![];
+        let mut resolve = |reference: &str| -> Result<Option<String>> {
+            if reference.contains("://") || reference.starts_with("data:") {
+                return Ok(None);
+            }
+            let path = base_dir.join(reference);
+            if !path.is_file() && media_dir.join(reference).is_file() {
+                return Ok(None);
+            }
+            let data = fs::read(&path)
+                .map_err(|e| Error::parse(Some(id.clone()), format!("can't read media {}: {}", path.display(), e)))?;
+            let name = hashed_name(&path, &data);
+            media.push((path, name.clone()));
+            Ok(Some(name))
+        };
+
+        let fields = card.fields.iter_mut().skip(1)
+            .chain(card.named.iter_mut().map(|(_, value)| value));
+        for field in fields {
+            match rewrite_references(field, &mut resolve) {
+                Ok(rewritten) => *field = rewritten,
+                Err(e) => errors.push(e),
+            }
+        }
+        card.media = media;
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::from_many(errors))
+    }
+}
+
+/// Points the content-hashed names `resolve_media` gave the media of the note `id` back at its files, relative to the
+/// note's file, so notes written back from Anki keep their references
+pub fn restore_references(flds: &str, id: &str, media: &[(PathBuf, String)]) -> String {
+    let base_dir = Path::new(split_id(id).0).parent().unwrap_or_else(|| Path::new(""));
+    let mut original = |name: &str| -> Result<Option<String>> {
+        Ok(media.iter()
+            .find(|(_, hashed)| hashed == name)
+            .and_then(|(path, _)| path.strip_prefix(base_dir).ok())
+            .map(|reference| reference.display().to_string()))
+    };
+    // finding the original never fails
+    rewrite_references(flds, &mut original).unwrap()
+}
+
+/// Copies files into the media folder next to the collection at `collection_path` and registers them in its media
+/// database, skipping ones that are there already
+pub fn add_media(collection_path: &Path, media: &[(PathBuf, String)]) -> Result<()> {
+    if media.is_empty() {
+        return Ok(());
+    }
+    let media_dir = collection_path.with_extension("media");
+    fs::create_dir_all(&media_dir).map_err(|e| Error::io(&media_dir, e))?;
+    let manager = MediaManager::new(&media_dir, collection_path.with_extension("media.db2"))?;
+    let mut ctx = manager.dbctx();
+    for (path, name) in media {
+        if media_dir.join(name).exists() {
+            continue;
+        }
+        let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+        manager.add_file(&mut ctx, name, &data)?;
+    }
+    Ok(())
+}
+
+fn hashed_name(path: &Path, data: &[u8]) -> String {
+    let hash = sha1::Sha1::from(data).digest().to_string();
+    match path.extension() {
+        Some(extension) => format!("{}.{}", hash, extension.to_string_lossy()),
+        None => hash,
+    }
+}
+
+/// Replaces the file of every `<img src="...">` and `[sound:...]` for which `resolve` returns a new one
+fn rewrite_references<F>(field: &str, resolve: &mut F) -> Result<String>
+where F: FnMut(&str) -> Result<Option<String>>
+{
+    let mut output = String::with_capacity(field.len());
+    let mut rest = field;
+    loop {
+        let sound = rest.find("[sound:");
+        let image = rest.find("<img");
+        let (start, end) = match (sound, image) {
+            (Some(s), i) if i.is_none_or(|i| s < i) => {
+                let start = s + "[sound:".len();
+                match rest[start..].find(']') {
+                    Some(length) => (start, start + length),
+                    None => break,
+                }
+            },
+            (_, Some(i)) => {
+                let tag_end = rest[i..].find('>').map_or(rest.len(), |e| i + e);
+                let tag = &rest[i..tag_end];
+                let src = ["src=\"", "src='"].iter()
+                    .find_map(|attribute| tag.find(attribute).map(|s| (s + attribute.len(), &attribute[4..])));
+                match src {
+                    Some((s, quote)) => match tag[s..].find(quote) {
+                        Some(length) => (i + s, i + s + length),
+                        None => {
+                            output += &rest[..tag_end];
+                            rest = &rest[tag_end..];
+                            continue;
+                        },
+                    },
+                    None => {
+                        output += &rest[..tag_end];
+                        rest = &rest[tag_end..];
+                        continue;
+                    },
+                }
+            },
+            _ => break,
+        };
+        output += &rest[..start];
+        match resolve(&rest[start..end])? {
+            Some(name) => output += &name,
+            None => output += &rest[start..end],
+        }
+        rest = &rest[end..];
+    }
+    output += rest;
+    Ok(output)
+}
+
+#[test]
+fn resolve() {
+    use crate::parsing::BatchReader;
+
+    let parse = |image: &str| BatchReader::from_string(vec![(
+        "test_files/media/card.qz".to_string(),
+        format!("---\ndeck: example\ntype: basic\n---\n![a diagram]({})\n---\n[sound:clip.mp3]\n", image).as_str(),
+    )]).parse().unwrap();
+
+    let mut decks = parse("diagram.png");
+    let result = resolve_media(&mut decks, Path::new("test_files/media/collection.media"));
+    assert!(result.is_err(), "Missing media is allowed");
+
+    let mut decks = parse("https://example.com/diagram.png");
+    let result = resolve_media(&mut decks, Path::new("test_files/media/collection.media"));
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let card = &decks[0].groups[0].cards[0];
+    let name = hashed_name(Path::new("clip.mp3"), b"ID3 not really audio\n");
+    assert_eq!(card.fields[2], format!("[sound:{}]", name));
+    assert_eq!(card.fields[1], "<img src=\"https://example.com/diagram.png\" alt=\"a diagram\">");
+    assert_eq!(card.media, vec![(PathBuf::from("test_files/media/clip.mp3"), name)]);
+}
+
+#[test]
+fn references() {
+    let mut seen = vec![];
+    let rewritten = rewrite_references(
+        "<img alt=\"a\" src=\"a.png\"> and <img src='https://x/b.png'>[sound:c.mp3]<br/>",
+        &mut |r: &str| {
+            seen.push(r.to_string());
+            Ok(if r.contains("://") { None } else { Some(format!("hashed-{}", r)) })
+        },
+    ).unwrap();
+
+    assert_eq!(seen, vec!["a.png", "https://x/b.png", "c.mp3"]);
+    assert_eq!(rewritten, "<img alt=\"a\" src=\"hashed-a.png\"> and <img src='https://x/b.png'>[sound:hashed-c.mp3]<br/>");
+}
+
+#[test]
+fn restore() {
+    let media = vec![
+        (PathBuf::from("notes/img/diagram.png"), "1234.png".to_string()),
+        (PathBuf::from("notes/clip.mp3"), "5678.mp3".to_string()),
+    ];
+    let flds = "notes/card.qz#1\u{1f}<img src=\"1234.png\" alt=\"a\">\u{1f}[sound:5678.mp3] [sound:other.mp3]";
+    assert_eq!(
+        restore_references(flds, "notes/card.qz#1", &media),
+        "notes/card.qz#1\u{1f}<img src=\"img/diagram.png\" alt=\"a\">\u{1f}[sound:clip.mp3] [sound:other.mp3]",
+    );
+}
@@ -0,0 +1,342 @@
+use std::{fs, path::Path};
+
+use anki::{collection::CollectionBuilder, notetype::{Notetype, NoteField, NoteFieldConfig, CardTemplate, CardTemplateConfig, NotetypeKind, all_stock_notetypes}, timestamp::TimestampSecs, types::Usn};
+use serde::Deserialize;
+
+use crate::{Error, Result, get_config};
+
+pub const ID_FIELD: &str = "Id";
+
+/// The stock notetypes that get a variant with an id field
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StockKind {
+    Basic,
+    BasicReversed,
+    BasicTyping,
+    Cloze,
+}
+
+/// The kind, config key and name of the variant of each cloned stock notetype
+const STOCK_VARIANTS: [(StockKind, &str, &str); 4] = [
+    (StockKind::Basic, "basic", "Basic (anc)"),
+    (StockKind::BasicReversed, "basic_reversed", "Basic (and reversed card) (anc)"),
+    (StockKind::BasicTyping, "basic_typing", "Basic (type in the answer) (anc)"),
+    (StockKind::Cloze, "cloze", "Cloze (anc)"),
+];
+
+/// Which stock notetype this is, told by its shape since names are translated and the order of the stock notetypes
+/// may change
+///
+/// Basic (optional reversed card) and stock notetypes added later, like image occlusion, are `None`.
+fn stock_kind(notetype: &Notetype) -> Option<StockKind> {
+    let typing = notetype.templates.iter().any(|t| t.config.q_format.contains("{{type:"));
+    match (notetype.config.kind == NotetypeKind::Cloze as i32, notetype.fields.len(), notetype.templates.len()) {
+        (true, 2, 1) => Some(StockKind::Cloze),
+        (false, 2, 1) if typing => Some(StockKind::BasicTyping),
+        (false, 2, 1) => Some(StockKind::Basic),
+        (false, 2, 2) => Some(StockKind::BasicReversed),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct DefinitionFile {
+    fields: Vec<String>,
+    #[serde(default)]
+    cloze: bool,
+}
+
+/// A notetype defined in `.anc/notetypes/<name>/`
+#[derive(Debug, PartialEq)]
+pub struct NotetypeDefinition {
+    pub name: String,
+    pub cloze: bool,
+    pub fields: Vec<String>, // always starting with the id field
+    pub templates: Vec<(String, String, String)>, // (name, front, back)
+    pub css: Option<String>,
+}
+
+/// Creates or updates every notetype defined in `.anc/notetypes`
+pub fn apply() -> Result<Vec<String>> {
+    let config = get_config()?;
+    let definitions = read_definitions(&config.config_dir.join("notetypes"))?;
+    if definitions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut collection = CollectionBuilder::new(config.anki_dir.join("collection.anki2")).build()?;
+    let mut output = vec![];
+    for definition in definitions {
+        let existing = collection.get_notetype_by_name(&definition.name)
+            .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+        let result = match existing {
+            None => {
+                let mut notetype = Notetype {
+                    name: definition.name.clone(),
+                    ..Default::default()
+                };
+                if definition.cloze {
+                    notetype.config.kind = NotetypeKind::Cloze as i32;
+                }
+                apply_definition(&mut notetype, &definition);
+                output.push(format!("Created notetype {}", definition.name));
+                collection.add_notetype(&mut notetype, false).map(|_| ())
+            },
+            Some(existing) => {
+                let mut notetype = (*existing).clone();
+                apply_definition(&mut notetype, &definition);
+                // changing fields or templates forces a full sync, so leave identical notetypes alone
+                if same_notetype(&notetype, &existing) {
+                    continue;
+                }
+                output.push(format!("Updated notetype {}", definition.name));
+                collection.update_notetype(&mut notetype, false).map(|_| ())
+            },
+        };
+        result.map_err(|e| Error::Collection(format!("{}: {}", definition.name, e.localized_description(&collection.tr))))?;
+    }
+    Ok(output)
+}
+
+/// Replaces fields, templates and CSS
+///
+/// Fields and templates with the same name as existing ones, ignoring case like Anki does, are kept as they are,
+/// including their name and ord, so existing
+/// notes and cards keep their content, and settings made in Anki, like sticky fields or browser formats, survive.
+/// Only the formats of templates come from the definition.
+fn apply_definition(notetype: &mut Notetype, definition: &NotetypeDefinition) {
+    notetype.fields = definition.fields.iter()
+        .map(|name| notetype.fields.iter()
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| note_field(name, None)))
+        .collect();
+    notetype.templates = definition.templates.iter()
+        .map(|(name, front, back)| {
+            let mut template = notetype.templates.iter()
+                .find(|t| t.name.eq_ignore_ascii_case(name))
+                .cloned()
+                .unwrap_or_else(|| CardTemplate {
+                    ord: None,
+                    mtime_secs: TimestampSecs(0),
+                    usn: Usn(0),
+                    name: name.clone(),
+                    config: CardTemplateConfig::default(),
+                });
+            template.config.q_format = front.clone();
+            template.config.a_format = back.clone();
+            template
+        })
+        .collect();
+    if let Some(css) = &definition.css {
+        notetype.config.css = css.clone();
+    }
+}
+
+fn note_field(name: &str, ord: Option<u32>) -> NoteField {
+    NoteField {
+        ord,
+        name: name.to_string(),
+        config: NoteFieldConfig {
+            font_name: "Liberation Sans".to_string(),
+            font_size: 20,
+            ..Default::default()
+        },
+    }
+}
+
+/// Adds copies of the stock Basic, Basic (and reversed card), Basic (type in the answer) and Cloze notetypes with an
+/// id field in front, returning their config keys and names
+///
+/// Variants that already exist are left as they are.
+pub fn add_stock_variants(anki_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut collection = CollectionBuilder::new(anki_dir.join("collection.anki2")).build()?;
+    let stock = all_stock_notetypes(&collection.tr);
+
+    let mut added = vec![];
+    for (kind, key, name) in STOCK_VARIANTS {
+        let mut notetype = match stock.iter().find(|n| stock_kind(n) == Some(kind)) {
+            Some(n) => n.clone(),
+            None => return Err(Error::Collection(format!("Anki has no stock notetype to base {} on", name))),
+        };
+        let existing = collection.get_notetype_by_name(name)
+            .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+        if existing.is_none() {
+            notetype.name = name.to_string();
+            notetype.fields.insert(0, note_field(ID_FIELD, None));
+            collection.add_notetype(&mut notetype, false)
+                .map_err(|e| Error::Collection(format!("{}: {}", name, e.localized_description(&collection.tr))))?;
+        }
+        added.push((key.to_string(), name.to_string()));
+    }
+    Ok(added)
+}
+
+fn same_notetype(a: &Notetype, b: &Notetype) -> bool {
+    a.config.css == b.config.css
+        && a.fields.iter().map(|f| (&f.name, f.ord)).eq(b.fields.iter().map(|f| (&f.name, f.ord)))
+        && a.templates.iter().map(|t| (&t.name, t.ord, &t.config.q_format, &t.config.a_format))
+            .eq(b.templates.iter().map(|t| (&t.name, t.ord, &t.config.q_format, &t.config.a_format)))
+}
+
+/// Reads every notetype directory
+///
+/// Each one contains a `notetype.toml` with the `fields` and whether it's a `cloze` notetype, an optional `style.css`,
+/// and either `front.html` and `back.html` or a `templates/<card name>/` directory with both for each card type.
+pub fn read_definitions(dir: &Path) -> Result<Vec<NotetypeDefinition>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| Error::io(dir, e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    entries.sort();
+
+    let (definitions, errors): (Vec<_>, Vec<_>) = entries.iter()
+        .map(|p| read_definition(p))
+        .partition(Result::is_ok);
+    if errors.is_empty() {
+        Ok(definitions.into_iter().map(Result::unwrap).collect())
+    } else {
+        Err(Error::from_many(errors.into_iter().map(|e| Error::Config(e.unwrap_err())).collect()))
+    }
+}
+
+fn read_definition(dir: &Path) -> Result<NotetypeDefinition, String> {
+    let name = dir.file_name().unwrap().to_string_lossy().to_string();
+    let file: DefinitionFile = fs::read_to_string(dir.join("notetype.toml"))
+        .map_err(|_| format!("Notetype {} is missing notetype.toml", name))
+        .and_then(|c| toml::from_str(&c).map_err(|e| format!("Notetype {}: {}", name, e)))?;
+
+    let mut fields: Vec<String> = file.fields.into_iter()
+        .filter(|f| !f.eq_ignore_ascii_case(ID_FIELD))
+        .collect();
+    fields.insert(0, ID_FIELD.to_string());
+
+    let read_template = |dir: &Path| -> Result<(String, String), String> {
+        let front = fs::read_to_string(dir.join("front.html"))
+            .map_err(|_| format!("Notetype {} is missing {}", name, dir.join("front.html").display()))?;
+        let back = fs::read_to_string(dir.join("back.html"))
+            .map_err(|_| format!("Notetype {} is missing {}", name, dir.join("back.html").display()))?;
+        Ok((front, back))
+    };
+    let templates_dir = dir.join("templates");
+    let templates = if templates_dir.is_dir() {
+        let mut template_dirs: Vec<_> = fs::read_dir(&templates_dir)
+            .map_err(|e| e.to_string())?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        template_dirs.sort();
+        template_dirs.iter()
+            .map(|t| {
+                let (front, back) = read_template(t)?;
+                Ok((t.file_name().unwrap().to_string_lossy().to_string(), front, back))
+            })
+            .collect::<Result<Vec<_>, String>>()?
+    } else {
+        let (front, back) = read_template(dir)?;
+        vec![("Card 1".to_string(), front, back)]
+    };
+    if file.cloze && templates.len() != 1 {
+        return Err(format!("Cloze notetype {} must have exactly one template", name));
+    }
+
+    Ok(NotetypeDefinition {
+        name,
+        cloze: file.cloze,
+        fields,
+        templates,
+        css: fs::read_to_string(dir.join("style.css")).ok(),
+    })
+}
+
+#[test]
+fn definitions() {
+    let result = read_definitions(Path::new("test_files/good/.anc/notetypes"));
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    assert_eq!(
+        result.unwrap(),
+        vec![NotetypeDefinition {
+            name: "vocab".to_string(),
+            cloze: false,
+            fields: vec!["Id".to_string(), "Word".to_string(), "Meaning".to_string()],
+            templates: vec![
+                ("Forward".to_string(), "{{Word}}\n".to_string(), "{{Meaning}}\n".to_string()),
+                ("Reverse".to_string(), "{{Meaning}}\n".to_string(), "{{Word}}\n".to_string()),
+            ],
+            css: Some(".card { font-size: 20px; }\n".to_string()),
+        }]
+    );
+}
+
+#[test]
+fn keeps_settings() {
+    let mut notetype = Notetype::default();
+    let mut word = note_field("Word", Some(1));
+    word.config.sticky = true;
+    word.config.font_name = "Noto Serif".to_string();
+    notetype.fields = vec![note_field("ID", Some(0)), word, note_field("Gone", Some(2))];
+    notetype.templates = vec![CardTemplate {
+        ord: Some(0),
+        mtime_secs: TimestampSecs(0),
+        usn: Usn(0),
+        name: "forward".to_string(),
+        config: CardTemplateConfig {
+            q_format: "old".to_string(),
+            q_format_browser: "{{Word}}".to_string(),
+            target_deck_id: 42,
+            ..Default::default()
+        },
+    }];
+
+    apply_definition(&mut notetype, &NotetypeDefinition {
+        name: "vocab".to_string(),
+        cloze: false,
+        fields: vec![ID_FIELD.to_string(), "Word".to_string(), "Meaning".to_string()],
+        templates: vec![("Forward".to_string(), "{{Word}}".to_string(), "{{Meaning}}".to_string())],
+        css: None,
+    });
+
+    assert_eq!(notetype.fields.iter().map(|f| (f.name.as_str(), f.ord)).collect::<Vec<_>>(), vec![("ID", Some(0)), ("Word", Some(1)), ("Meaning", None)]);
+    assert!(notetype.fields[1].config.sticky);
+    assert_eq!(notetype.fields[1].config.font_name, "Noto Serif");
+    let template = &notetype.templates[0];
+    assert_eq!(template.name, "forward");
+    assert_eq!((template.ord, template.config.q_format.as_str(), template.config.a_format.as_str()), (Some(0), "{{Word}}", "{{Meaning}}"));
+    assert_eq!((template.config.q_format_browser.as_str(), template.config.target_deck_id), ("{{Word}}", 42));
+}
+
+#[test]
+fn stock_kinds() {
+    let notetype = |cloze: bool, fields: &[&str], templates: &[&str]| {
+        let mut notetype = Notetype::default();
+        if cloze {
+            notetype.config.kind = NotetypeKind::Cloze as i32;
+        }
+        notetype.fields = fields.iter().map(|f| note_field(f, None)).collect();
+        notetype.templates = templates.iter()
+            .map(|q| CardTemplate {
+                ord: None,
+                mtime_secs: TimestampSecs(0),
+                usn: Usn(0),
+                name: "Card".to_string(),
+                config: CardTemplateConfig { q_format: q.to_string(), ..Default::default() },
+            })
+            .collect();
+        notetype
+    };
+
+    assert_eq!(stock_kind(&notetype(false, &["Front", "Back"], &["{{Front}}"])), Some(StockKind::Basic));
+    assert_eq!(stock_kind(&notetype(false, &["Front", "Back"], &["{{Front}}", "{{Back}}"])), Some(StockKind::BasicReversed));
+    assert_eq!(stock_kind(&notetype(false, &["Front", "Back", "Add Reverse"], &["{{Front}}", "{{#Add Reverse}}{{Back}}{{/Add Reverse}}"])), None);
+    assert_eq!(stock_kind(&notetype(false, &["Front", "Back"], &["{{Front}}\n{{type:Back}}"])), Some(StockKind::BasicTyping));
+    assert_eq!(stock_kind(&notetype(true, &["Text", "Back Extra"], &["{{cloze:Text}}"])), Some(StockKind::Cloze));
+    // image occlusion
+    assert_eq!(stock_kind(&notetype(true, &["Occlusion", "Image", "Header", "Back Extra", "Comments"], &["{{cloze:Occlusion}}"])), None);
+}
@@ -1,19 +1,44 @@
-use std::{io::{self, BufRead, Read, Write}, fs::File, path::PathBuf, process::{Command, Stdio, ChildStdout}, thread};
+use std::{io::{self, BufRead, Read}, fs::File, path::{Path, PathBuf}};
 
 use itertools::Itertools;
-use html_escape::encode_text;
-use serde::Deserialize;
+use html_escape::{encode_text, decode_html_entities};
+use pulldown_cmark::{html::push_html, Options, Parser};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 
-use crate::cards::{Card, TypeGroup, Deck};
+use crate::{Error, Result, cards::{Card, TypeGroup, Deck}, defaults::{Defaults, merge}, hooks::{Hooks, PRE_PARSE}, math::{split, Span}};
 
 pub struct BatchReader<T> where T: Read {
     readers: Vec<(Option<String>, io::BufReader<T>)>,
+    format: Format,
+    defaults: Option<Defaults>,
 }
 
 impl<T> BatchReader<T> where T: Read {
-    pub fn parse(self) -> Result<Vec<Deck>, Vec<String>> {
+    /// Sets the format of notes whose frontmatter doesn't have one
+    pub fn with_format(mut self, format: Format) -> BatchReader<T> {
+        self.format = format;
+        self
+    }
+
+    /// Merges directory defaults into the frontmatter of every note
+    pub fn with_defaults(mut self, defaults: Defaults) -> BatchReader<T> {
+        self.defaults = Some(defaults);
+        self
+    }
+
+    pub fn parse(self) -> Result<Vec<Deck>> {
+        let format = self.format;
+        let mut defaults = self.defaults;
         let (cards, card_errors): (Vec<_>, Vec<_>) = self.readers.into_iter()
-            .map(|(id, p)| parse(p, id))
+            .map(|(id, p)| {
+                let note_defaults = match (defaults.as_mut(), &id) {
+                    (None, _) => Mapping::new(),
+                    (Some(d), Some(path)) => d.for_file(Path::new(path))?,
+                    (Some(d), None) => d.project().clone(),
+                };
+                parse(p, id, format, &note_defaults)
+            })
             .partition(|result| result.is_ok());
 
         let errors: Vec<_> = card_errors.into_iter()
@@ -21,7 +46,7 @@ impl<T> BatchReader<T> where T: Read {
             .collect();
 
         if errors.len() > 0 {
-            return Err(errors.into_iter().flatten().collect());
+            return Err(Error::from_many(errors));
         }
 
         Ok(cards.into_iter()
@@ -59,134 +84,255 @@ impl BatchReader<&[u8]> {
                         .map(|(id, card)| (id, card.as_bytes()))
                         .map(|(id, bytes)| (Some(id), io::BufReader::new(bytes)))
                         .collect(),
+            format: Format::default(),
+            defaults: None,
         }
     }
 }
 
-impl BatchReader<ChildStdout> {
-    pub fn from_stdout(input: ChildStdout) -> BatchReader<ChildStdout> {
+impl<'a> BatchReader<&'a [u8]> {
+    /// Notes in the .qz format that a hook printed, which don't belong to any file
+    pub fn from_hook(output: &'a [u8]) -> BatchReader<&'a [u8]> {
         BatchReader {
-            readers: vec![(None, io::BufReader::new(input))],
+            readers: vec![(None, io::BufReader::new(output))],
+            format: Format::default(),
+            defaults: None,
         }
     }
 }
 
 impl BatchReader<File> {
-    pub fn from_files(paths: Vec<PathBuf>) -> BatchReader<File> {
-        BatchReader {
+    pub fn from_files(paths: Vec<PathBuf>) -> Result<BatchReader<File>> {
+        Ok(BatchReader {
             readers: paths.into_iter()
                         .map(|p| {
-                            let file = File::open(p.clone())
-                                .expect(format!("Could not open {:?}", p).as_str());
-                            (Some(p.display().to_string()), io::BufReader::new(file))
+                            let file = File::open(&p)
+                                .map_err(|e| Error::io(&p, e))?;
+                            Ok((Some(p.display().to_string()), io::BufReader::new(file)))
                         })
-                        .collect(),
-        }
+                        .collect::<Result<_>>()?,
+            format: Format::default(),
+            defaults: None,
+        })
     }
 }
 
-pub fn parse_files(config_dir: PathBuf, paths: Vec<PathBuf>) -> Result<Vec<Deck>, Vec<String>> {
-    let path = config_dir.join("hooks/pre-parse");
-    if path.exists() {
-        let mut process = Command::new(path.display().to_string())
-            .current_dir(config_dir.join("hooks"))
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        thread::spawn(move || {
-            let mut stdin = process.stdin.take().unwrap();
-            for p in paths {
-                stdin.write(p.display().to_string().as_bytes()).unwrap();
-            }
-        });
-        let output = process.stdout.take().unwrap();
-        BatchReader::from_stdout(output)
-            .parse()
+/// Parses .qz files, either directly or through the `pre-parse` hook, and files with a converter through it
+pub fn parse_files(config_dir: PathBuf, paths: Vec<PathBuf>, format: Format, hooks: &Hooks) -> Result<Vec<Deck>> {
+    let (to_convert, paths): (Vec<_>, Vec<_>) = paths.into_iter().partition(|p| hooks.converts(p));
+    let mut converted = vec![];
+    let mut errors = vec![];
+    for path in to_convert {
+        match hooks.convert(&path) {
+            Ok(text) => converted.push((path.display().to_string(), text.unwrap_or_default())),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    let converted = BatchReader::from_string(converted.iter().map(|(p, t)| (p.clone(), t.as_str())).collect())
+        .with_format(format)
+        .with_defaults(Defaults::load(&config_dir)?)
+        .parse();
+    let defaults = Defaults::load(&config_dir)?;
+    let parsed = if paths.is_empty() {
+        Ok(vec![])
+    } else if hooks.has(PRE_PARSE) {
+        hooks.pre_parse(&paths, format, defaults)
     } else {
         BatchReader::from_files(paths)
-            .parse()
+            .and_then(|r| r.with_format(format).with_defaults(defaults).parse())
+    };
+
+    let mut decks = vec![];
+    for result in [converted, parsed] {
+        match result {
+            Ok(mut d) => decks.append(&mut d),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(decks)
+    } else {
+        Err(Error::from_many(errors))
     }
 }
 
-pub fn parse_from_file(filename: &str) -> Result<Vec<(String, Card)>, Vec<String>> {
+pub fn parse_from_file(filename: &str) -> Result<Vec<(String, Card)>> {
     let file = File::open(filename)
-        .map_err(|_| vec![format!("Could not open {}", filename)])?;
+        .map_err(|e| Error::io(filename, e))?;
     let reader = io::BufReader::new(file);
-    parse(reader, Some(filename.to_string()))
+    parse(reader, Some(filename.to_string()), Format::default(), &Mapping::new())
+}
+
+/// Either whitespace separated or a list, whose items may contain spaces
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Tags {
+    Line(String),
+    List(Vec<String>),
 }
 
-#[derive(Deserialize)]
+impl Tags {
+    /// Anki's space separated form, with spaces inside list items turned into underscores
+    pub fn to_anki(&self) -> String {
+        match self {
+            Tags::Line(line) => line.split_whitespace().join(" "),
+            Tags::List(tags) => tags.iter()
+                .map(|t| t.split_whitespace().join("_"))
+                .filter(|t| !t.is_empty())
+                .join(" "),
+        }
+    }
+}
+
+/// How the text of fields is turned into the HTML Anki stores
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Escaped, with line breaks kept
+    #[default]
+    Plain,
+    Html,
+    Markdown,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct Frontmatter {
     deck: String,
     r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<String>,
-    tags: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Tags>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     html: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Format>,
+}
+
+impl Frontmatter {
+    pub fn new(deck: String, r#type: String, tags: Option<String>, html: Option<bool>) -> Frontmatter {
+        Frontmatter {
+            deck,
+            r#type,
+            id: None,
+            tags: tags.map(Tags::Line),
+            html,
+            format: None,
+        }
+    }
+
+    /// `format` wins over `html`, and `default` is used if neither is set
+    fn format(&self, default: Format) -> Format {
+        match (self.format, self.html) {
+            (Some(format), _) => format,
+            (None, Some(true)) => Format::Html,
+            (None, Some(false)) => Format::Plain,
+            (None, None) => default,
+        }
+    }
+
+    /// The YAML between the `---` lines, ending with a newline
+    pub fn to_yaml(&self) -> String {
+        let yaml = serde_yaml::to_string(self).unwrap();
+        format!("{}\n", yaml.trim_start_matches("---\n").trim_end())
+    }
 }
 
 pub fn parse<T>(
     reader: io::BufReader<T>,
-    id: Option<String>
-) -> Result<Vec<(String, Card)>, Vec<String>>
+    id: Option<String>,
+    default_format: Format,
+    defaults: &Mapping,
+) -> Result<Vec<(String, Card)>>
 where T: Read
 {
-    let lines = reader.lines();
-
-    let (cards, errors) = lines.into_iter()
-        .map(|l| l.unwrap())
-        .fold(vec![vec![]], |mut cards: Vec<Vec<String>>, l| {
-            if l.trim() == "---" {
-                let last_note = cards.last_mut().unwrap();
-                last_note.push("".to_string());
-            } else if l.trim() == "###" {
-                cards.push(Vec::new());
-            } else {
-                let last_note = cards.last_mut().unwrap();
-                let last_part = last_note.last_mut().unwrap();
-                *last_part += &l;
-                *last_part += "\n";
+    let lines: Vec<String> = reader.lines()
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|e| Error::parse(id.clone(), format!("could not read: {}", e)))?;
+
+    let error_at = |note: usize, line: usize, column: usize, message: String| Error::parse_at(
+        id.clone(),
+        line,
+        column,
+        lines.get(line - 1).cloned(),
+        format!("note {}: {}", note, message),
+    );
+
+    let mut notes = vec![RawNote { line: 1, parts: vec![] }];
+    let mut stray = vec![];
+    for (i, l) in lines.iter().enumerate() {
+        if let Some(name) = separator(l) {
+            notes.last_mut().unwrap().parts.push((i + 2, name.map(|n| n.to_string()), String::new()));
+        } else if l.trim() == "###" {
+            notes.push(RawNote { line: i + 2, parts: vec![] });
+        } else {
+            match notes.last_mut().unwrap().parts.last_mut() {
+                Some((_, _, part)) => {
+                    *part += l;
+                    *part += "\n";
+                },
+                None if l.trim().is_empty() => {},
+                None => stray.push(error_at(notes.len(), i + 1, 1, "text before the frontmatter".to_string())),
             }
-            cards
-        })
-        .into_iter()
+        }
+    }
+    if !stray.is_empty() {
+        return Err(Error::from_many(stray));
+    }
+
+    let (cards, errors) = notes.into_iter()
         .enumerate()
         .map(|(i, n)| {
-            if n.len() == 0 { return Err("empty card".to_string()); }
-            let frontmatter: Frontmatter = {
-                let val = n.get(0).unwrap();
-                let yaml = serde_yaml::from_str(val);
-                if let Err(e) = yaml { return Err(format!("error parsing frontmatter: {}", e)) };
-                yaml.unwrap()
-            };
-            let note_id = {
-                let i = frontmatter.id.or(id.clone().map(|f| format!("{}#{}", f, i + 1)));
-                if i.is_none() { return Err("An id is required as part of the frontmatter".to_string())}
-                i.unwrap()
-            };
-            let mut parts = {
-                if frontmatter.html.unwrap_or(false) {
-                    n.into_iter().skip(1)
-                        .map(|p| plaintext(p))
-                        .collect()
-                } else {
-                    n.into_iter().skip(1)
-                        .map(|p| p.trim().to_string())
-                        .collect()
+            if n.parts.is_empty() {
+                return Err(error_at(i + 1, n.line.min(lines.len().max(1)), 1, "empty card".to_string()));
+            }
+            let (frontmatter_line, frontmatter_name, yaml) = &n.parts[0];
+            if frontmatter_name.is_some() {
+                return Err(error_at(i + 1, frontmatter_line - 1, 1, "the frontmatter can't be named".to_string()));
+            }
+            let yaml_error = |e: serde_yaml::Error| {
+                let message = format!("error parsing frontmatter: {}", yaml_message(&e));
+                match e.location() {
+                    Some(l) => error_at(i + 1, frontmatter_line + l.line() - 1, l.column(), message),
+                    None => error_at(i + 1, frontmatter_line - 1, 1, message),
                 }
             };
+            let frontmatter: Frontmatter = if defaults.is_empty() {
+                serde_yaml::from_str(yaml).map_err(yaml_error)?
+            } else {
+                // serde_yaml fails on empty documents
+                let own = if yaml.trim().is_empty() { Value::Null } else { serde_yaml::from_str(yaml).map_err(yaml_error)? };
+                let merged = match own {
+                    Value::Mapping(own) => Value::Mapping(merge(defaults, own)),
+                    Value::Null => Value::Mapping(defaults.clone()),
+                    other => other,
+                };
+                serde_yaml::from_value(merged).map_err(yaml_error)?
+            };
+            let format = frontmatter.format(default_format);
+            let note_id = frontmatter.id.or(id.clone().map(|f| format!("{}#{}", f, i + 1)))
+                .ok_or_else(|| error_at(i + 1, frontmatter_line - 1, 1, "An id is required as part of the frontmatter".to_string()))?;
             let mut fields = vec![note_id];
-            fields.append(&mut parts);
-            Ok((
-                frontmatter.deck,
-                Card::new(
-                    frontmatter.r#type,
-                    fields,
-                    frontmatter.tags,
-                )
-            ))
+            let mut named = vec![];
+            for (line, name, p) in n.parts.into_iter().skip(1) {
+                let value = render(p, format);
+                match name {
+                    Some(name) => named.push((name, value)),
+                    None if named.is_empty() => fields.push(value),
+                    None => return Err(error_at(i + 1, line - 1, 1, "unnamed field after a named one".to_string())),
+                }
+            }
+            let mut card = Card::new(
+                frontmatter.r#type,
+                fields,
+                frontmatter.tags.map(|t| t.to_anki()).filter(|t| !t.is_empty()),
+            );
+            card.named = named;
+            card.format = format;
+            Ok((frontmatter.deck, card))
         })
-        .partition::<Vec<Result<(String, Card), String>>, _>(Result::is_ok);
+        .partition::<Vec<Result<(String, Card)>>, _>(Result::is_ok);
 
     if errors.is_empty() {
         Ok(
@@ -195,18 +341,234 @@ where T: Read
                 .collect()
         )
     } else {
-        Err(
+        Err(Error::from_many(
             errors.into_iter()
                 .map(|e| e.unwrap_err())
                 .collect()
-        )
+        ))
+    }
+}
+
+/// A note that was split into frontmatter and fields already, like the ones a JSON hook returns
+///
+/// `own` is laid over `defaults` like the frontmatter of a .qz note, and `default_id` is used if it has no `id`.
+pub(crate) fn card_from_parts(
+    own: Mapping,
+    defaults: &Mapping,
+    default_id: String,
+    default_format: Format,
+    fields: Vec<String>,
+    named: Vec<(String, String)>,
+) -> std::result::Result<(String, Card), String> {
+    let frontmatter: Frontmatter = serde_yaml::from_value(Value::Mapping(merge(defaults, own)))
+        .map_err(|e| format!("error parsing frontmatter: {}", yaml_message(&e)))?;
+    let format = frontmatter.format(default_format);
+    let mut card = Card::new(
+        frontmatter.r#type,
+        std::iter::once(frontmatter.id.unwrap_or(default_id))
+            .chain(fields.into_iter().map(|f| render(f, format)))
+            .collect(),
+        frontmatter.tags.map(|t| t.to_anki()).filter(|t| !t.is_empty()),
+    );
+    card.named = named.into_iter()
+        .map(|(name, value)| (name, render(value, format)))
+        .collect();
+    card.format = format;
+    Ok((frontmatter.deck, card))
+}
+
+/// The parts of a note between `---` lines, before any of them is interpreted
+struct RawNote {
+    line: usize, // where the note starts
+    parts: Vec<(usize, Option<String>, String)>, // (first line, field name, text)
+}
+
+/// `Some(None)` for a `---` line and `Some(Some(name))` for a `--- Name` line, which starts a named field
+pub(crate) fn separator(line: &str) -> Option<Option<&str>> {
+    let line = line.trim();
+    if line == "---" {
+        Some(None)
+    } else {
+        line.strip_prefix("--- ").map(|name| Some(name.trim()))
+    }
+}
+
+/// serde_yaml's message without the location it appends, which is relative to the frontmatter
+fn yaml_message(error: &serde_yaml::Error) -> String {
+    let message = error.to_string();
+    match message.rfind(" at line ") {
+        Some(i) if error.location().is_some() => message[..i].to_string(),
+        _ => message,
+    }
+}
+
+/// The HTML Anki stores for the text of a field
+fn render(text: String, format: Format) -> String {
+    match format {
+        Format::Plain => plaintext(text),
+        Format::Html => text.trim().to_string(),
+        Format::Markdown => markdown(&text),
     }
 }
 
 fn plaintext(text: String) -> String {
-    let stripped = text.trim();
-    let encoded = encode_text(stripped);
-    encoded.replace("\n", "<br/>")
+    split(text.trim(), false).into_iter()
+        .map(|span| match span {
+            Span::Text(text) => plain_images(&encode_text(&text).replace("\n", "<br/>")),
+            math => math.to_anki(),
+        })
+        .collect()
+}
+
+fn markdown(text: &str) -> String {
+    // math is swapped for placeholders markdown leaves alone, then put back
+    let mut source = String::new();
+    let mut math = vec![];
+    for span in split(text, true) {
+        match span {
+            Span::Text(text) => source += &text,
+            span => {
+                source += &math_placeholder(math.len());
+                math.push(span.to_anki());
+            },
+        }
+    }
+
+    let mut html = String::new();
+    push_html(&mut html, Parser::new_ext(&source, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH));
+    for (i, m) in math.iter().enumerate() {
+        html = html.replace(&math_placeholder(i), m);
+    }
+    let html = html.trim_end();
+    // a lone paragraph would only add a margin around the field
+    match html.strip_prefix("<p>").and_then(|h| h.strip_suffix("</p>")) {
+        Some(inner) if !inner.contains("<p>") => inner.to_string(),
+        _ => html.to_string(),
+    }
+}
+
+/// Turns `![alt](file)` into an image, like in markdown
+fn plain_images(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("![") {
+        let image = rest[start + 2..].find("](")
+            .map(|alt_length| start + 2 + alt_length)
+            .and_then(|alt_end| rest[alt_end + 2..].find(')').map(|src_length| (alt_end, alt_end + 2 + src_length)));
+        let (alt_end, src_end) = match image {
+            Some(i) => i,
+            None => break,
+        };
+        output += &rest[..start];
+        output += &format!(
+            "<img src=\"{}\" alt=\"{}\">",
+            rest[alt_end + 2..src_end].replace('"', "&quot;"),
+            rest[start + 2..alt_end].replace('"', "&quot;"),
+        );
+        rest = &rest[src_end + 1..];
+    }
+    output + rest
+}
+
+fn math_placeholder(index: usize) -> String {
+    format!("\u{e000}{}\u{e001}", index)
+}
+
+/// Undoes `plaintext` for a field coming from Anki
+fn from_plaintext(field: &str) -> String {
+    let lines = field.replace("<br/>", "\n")
+        .replace("<br />", "\n")
+        .replace("<br>", "\n");
+    decode_html_entities(&markdown_images(&lines)).to_string()
+}
+
+/// Undoes `plain_images`, turning every `<img>` with a `src` back into `![alt](src)`
+fn markdown_images(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("<img") {
+        let end = match rest[start..].find('>') {
+            Some(length) => start + length + 1,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        output += &rest[..start];
+        match attribute(tag, "src") {
+            Some(src) => output += &format!("![{}]({})", attribute(tag, "alt").unwrap_or(""), src),
+            None => output += tag,
+        }
+        rest = &rest[end..];
+    }
+    output + rest
+}
+
+/// The value of `name` in an HTML tag, quoted with either kind of quote
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    ["\"", "'"].iter().find_map(|quote| {
+        let start = tag.find(&format!(" {}={}", name, quote))? + name.len() + 3;
+        tag[start..].find(quote).map(|length| &tag[start..start + length])
+    })
+}
+
+/// Whether the fields stored in Anki (`flds`, id first) can go into a .qz file as notes in `format`
+///
+/// Plain fields have to come out the same when the file is saved again, which isn't the case for markup added in Anki's
+/// editor or a `$` that would turn into math, and no field may contain a line that would split up the note.
+pub fn can_write_back(flds: &str, format: Format) -> bool {
+    let splits = |f: &str| f.lines().any(|l| separator(l).is_some() || l.trim() == "###");
+    flds.split('\u{1f}').skip(1).all(|f| match format {
+        Format::Markdown => false,
+        Format::Html => !splits(f),
+        Format::Plain => {
+            let text = from_plaintext(f);
+            !splits(&text) && plaintext(text) == f
+        },
+    })
+}
+
+/// Rewrites the fields of the note at `index` in the text of a .qz file, keeping its frontmatter and every other note
+///
+/// `format` is the one the note was parsed with. Markdown can't be recovered from the HTML in Anki, so notes in markdown
+/// are refused.
+pub fn replace_fields(text: &str, index: usize, fields: &[String], format: Format) -> Result<String> {
+    if format == Format::Markdown {
+        return Err(Error::parse(None, format!("note {} is written in markdown, so edits made in Anki can't be written back", index + 1)));
+    }
+    let mut output = vec![];
+    let mut note = 0;
+    let mut part = 0;
+    let mut replaced = false;
+    for line in text.lines() {
+        if line.trim() == "###" {
+            note += 1;
+            part = 0;
+            output.push(line.to_string());
+        } else if note != index {
+            output.push(line.to_string());
+        } else if separator(line).is_some() {
+            part += 1;
+            if part == 2 {
+                // the fields from Anki are positional, so a name here would be wrong
+                output.push("---".to_string());
+                output.push(
+                    fields.iter()
+                        .map(|f| if format == Format::Html { f.to_string() } else { from_plaintext(f) })
+                        .join("\n---\n")
+                );
+                replaced = true;
+            } else if part < 2 {
+                output.push(line.to_string());
+            }
+        } else if part < 2 {
+            output.push(line.to_string());
+        }
+    }
+
+    if replaced {
+        Ok(output.join("\n") + "\n")
+    } else {
+        Err(Error::parse(None, format!("note {} not found", index + 1)))
+    }
 }
 
 #[test]
@@ -224,11 +586,188 @@ fn basic() {
         Card {
             model: "basic".to_string(),
             fields: vec!["test_files/good/basic.qz#1".to_string(), "Question".to_string(), "Answer".to_string()],
-            tags: Some("example2 example3".to_string())
+            tags: Some("example2 example3".to_string()),
+            named: vec![],
+            format: Format::Plain,
+            media: vec![],
+        }
+    )
+}
+
+#[test]
+fn html() {
+    let result = BatchReader::from_string(vec![(
+        "html.qz".to_string(),
+        "---\ndeck: example\ntype: basic\nhtml: true\n---\n<b>Question</b>\n---\na < b",
+    )]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let card = &result.unwrap()[0].groups[0].cards[0];
+    assert_eq!(card.fields, vec!["html.qz#1", "<b>Question</b>", "a < b"]);
+
+    let result = BatchReader::from_string(vec![(
+        "plain.qz".to_string(),
+        "---\ndeck: example\ntype: basic\n---\n<b>Question</b>\n---\nline one\nline two",
+    )]).parse();
+
+    let card = &result.unwrap()[0].groups[0].cards[0];
+    assert_eq!(card.fields, vec!["plain.qz#1", "&lt;b&gt;Question&lt;/b&gt;", "line one<br/>line two"]);
+}
+
+#[test]
+fn markdown_format() {
+    let result = BatchReader::from_string(vec![(
+        "markdown.qz".to_string(),
+        "---\ndeck: example\ntype: basic\nformat: markdown\n---\nWhat is *a* < b?\n---\n- one\n- two\n\n| a | b |\n|---|---|\n| 1 | 2 |\n",
+    )]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let card = &result.unwrap()[0].groups[0].cards[0];
+    assert_eq!(card.fields[1], "What is <em>a</em> &lt; b?");
+    assert!(card.fields[2].starts_with("<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n<table>"), "{}", card.fields[2]);
+
+    let result = BatchReader::from_string(vec![(
+        "default.qz".to_string(),
+        "---\ndeck: example\ntype: basic\n---\n**Question**\n###\n---\ndeck: example\ntype: basic\nhtml: false\n---\n**Question**\n",
+    )]).with_format(Format::Markdown).parse();
+
+    let cards = &result.unwrap()[0].groups[0].cards;
+    assert_eq!(cards[0].fields[1], "<strong>Question</strong>");
+    assert_eq!(cards[1].fields[1], "**Question**");
+}
+
+#[test]
+fn math() {
+    let result = BatchReader::from_string(vec![(
+        "math.qz".to_string(),
+        "---\ndeck: example\ntype: basic\n---\nIs $a < b$ for $5?\n---\n$$\na_1 * b_1\n$$\n###\n---\ndeck: example\ntype: basic\nformat: markdown\n---\n*If* \\(a_1 < b_1\\)\n---\n$$a * b * c$$\n",
+    )]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let cards = &result.unwrap()[0].groups[0].cards;
+    assert_eq!(cards[0].fields[1..], ["Is \\(a < b\\) for $5?", "\\[\na_1 * b_1\n\\]"]);
+    assert_eq!(cards[1].fields[1..], ["<em>If</em> \\(a_1 < b_1\\)", "\\[a * b * c\\]"]);
+}
+
+#[test]
+fn directory_defaults() {
+    let config_dir = Path::new("test_files/defaults/.anc").canonicalize().unwrap();
+    let id = config_dir.parent().unwrap().join("biology/cells/a.qz").display().to_string();
+    let result = BatchReader::from_string(vec![(
+        id,
+        "---\n---\nQuestion\n---\nAnswer\n###\n---\ntype: cloze\ntags: mitosis\n---\n{{c1::Answer}}\n",
+    )]).with_defaults(Defaults::load(&config_dir).unwrap()).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let decks = result.unwrap();
+    assert_eq!(decks[0].name, "Biology::Cells");
+    assert_eq!(decks[0].groups[0].cards[0].tags.as_deref(), Some("anc biology cells"));
+    assert_eq!(decks[0].groups[1].model, "cloze");
+    assert_eq!(decks[0].groups[1].cards[0].tags.as_deref(), Some("anc biology cells mitosis"));
+}
+
+#[test]
+fn tag_list() {
+    let result = BatchReader::from_string(vec![(
+        "tags.qz".to_string(),
+        "---\ndeck: example\ntype: basic\ntags:\n  - cell biology\n  - Biology::Cells\n---\nQuestion\n###\n---\ndeck: example\ntype: basic\ntags: ''\n---\nQuestion\n",
+    )]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let cards = &result.unwrap()[0].groups[0].cards;
+    assert_eq!(cards[0].tags.as_deref(), Some("cell_biology Biology::Cells"));
+    assert_eq!(cards[1].tags, None);
+}
+
+#[test]
+fn frontmatter_round_trip() {
+    let frontmatter = Frontmatter::new("A::B".to_string(), "basic".to_string(), Some("x y".to_string()), Some(true));
+    let text = format!("---\n{}---\nQuestion\n---\nAnswer\n", frontmatter.to_yaml());
+    let result = BatchReader::from_string(vec![("pulled.qz".to_string(), text.as_str())]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let decks = result.unwrap();
+    assert_eq!(decks[0].name, "A::B");
+    assert_eq!(
+        decks[0].groups[0].cards[0],
+        Card {
+            model: "basic".to_string(),
+            fields: vec!["pulled.qz#1".to_string(), "Question".to_string(), "Answer".to_string()],
+            tags: Some("x y".to_string()),
+            named: vec![],
+            format: Format::Html,
+            media: vec![],
         }
     )
 }
 
+#[test]
+fn replace() {
+    let text = "---\ndeck: example\ntype: basic\n---\nQuestion\n---\nAnswer\n###\n---\ndeck: example\ntype: basic\n---\nOther\n---\nOld\n";
+    let fields = vec!["Other".to_string(), "New &amp; improved<br>answer".to_string()];
+
+    assert_eq!(
+        replace_fields(text, 1, &fields, Format::Plain).unwrap(),
+        "---\ndeck: example\ntype: basic\n---\nQuestion\n---\nAnswer\n###\n---\ndeck: example\ntype: basic\n---\nOther\n---\nNew & improved\nanswer\n"
+    );
+    assert!(replace_fields(text, 2, &fields, Format::Plain).is_err());
+    assert!(replace_fields(text, 1, &fields, Format::Markdown).is_err());
+}
+
+#[test]
+fn error_location() {
+    let result = parse_from_file("test_files/bad_frontmatter.qz");
+
+    match result {
+        Err(Error::Parse { file, line, column, snippet, .. }) => {
+            assert_eq!(file.as_deref(), Some("test_files/bad_frontmatter.qz"));
+            assert_eq!(line, Some(2));
+            assert_eq!(column, Some(1));
+            assert_eq!(snippet.as_deref(), Some("deck, example"));
+        },
+        r => panic!("Expected a parse error, got {:?}", r.err()),
+    }
+
+    let result = BatchReader::from_string(vec![(
+        "column.qz".to_string(),
+        "---\ndeck: example\ntype: basic\n---\nQuestion\n###\n---\ndeck: example: other\ntype: basic\n---\nQuestion\n",
+    )]).parse();
+    assert_eq!(
+        result.err().unwrap().to_string(),
+        "column.qz:8:14: note 2: error parsing frontmatter: mapping values are not allowed in this context\n  |\n8 | deck: example: other\n  |              ^"
+    );
+
+    let result = BatchReader::from_string(vec![(
+        "stray.qz".to_string(),
+        "---\ndeck: example\ntype: basic\n---\nQuestion\n###\n\n###\nstray\n",
+    )]).parse();
+    let error = result.err().unwrap();
+    let errors = error.errors();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().starts_with("stray.qz:9:1: note 3: text before the frontmatter"), "{}", errors[0]);
+}
+
+#[test]
+fn named_fields() {
+    let result = BatchReader::from_string(vec![(
+        "named.qz".to_string(),
+        "---\ndeck: example\ntype: vocab\n---\nWord\n--- Example\nIn a sentence\n--- notes\nRare\n",
+    )]).parse();
+
+    assert!(result.is_ok(), "Errors: {:?}", result.err());
+    let card = &result.unwrap()[0].groups[0].cards[0];
+    assert_eq!(card.fields, vec!["named.qz#1", "Word"]);
+    let names: Vec<String> = ["Id", "Word", "Meaning", "Example", "Notes"].iter().map(|n| n.to_string()).collect();
+    assert_eq!(card.resolve_fields(&names).unwrap(), vec!["named.qz#1", "Word", "", "In a sentence", "Rare"]);
+    assert!(card.resolve_fields(&names[..3]).is_err(), "Unknown field names are allowed");
+
+    let result = BatchReader::from_string(vec![(
+        "mixed.qz".to_string(),
+        "---\ndeck: example\ntype: vocab\n--- Word\nWord\n---\nMeaning\n",
+    )]).parse();
+    assert!(result.is_err(), "Positional fields after named ones are allowed");
+}
+
 #[test]
 fn nonexistent() {
     let result = parse_from_file("test_files/nonexistent_basic");
@@ -241,3 +780,28 @@ fn bad_frontmatter() {
 
     assert!(result.is_err(), "Bad frontmatter is allowed");
 }
+
+#[test]
+fn image_round_trip() {
+    let field = "A diagram: ![cell \"wall\"](diagram.png)\nand <b>text</b>".to_string();
+    let html = plaintext(field.clone());
+    assert_eq!(html, "A diagram: <img src=\"diagram.png\" alt=\"cell &quot;wall&quot;\"><br/>and &lt;b&gt;text&lt;/b&gt;");
+
+    let written_back = from_plaintext(&html);
+    assert_eq!(written_back, field);
+    assert_eq!(plaintext(written_back), html);
+    assert_eq!(from_plaintext("<img alt='a' src='b.png'>"), "![a](b.png)");
+}
+
+#[test]
+fn write_back_check() {
+    assert!(can_write_back("a.qz#1\u{1f}a &lt; b<br/>c\u{1f}<img src=\"x.png\" alt=\"x\">", Format::Plain));
+    // markup from Anki's editor would be escaped on the next save
+    assert!(!can_write_back("a.qz#1\u{1f}a <b>word</b>", Format::Plain));
+    assert!(can_write_back("a.qz#1\u{1f}a <b>word</b>", Format::Html));
+    // and a dollar sign would become math
+    assert!(!can_write_back("a.qz#1\u{1f}costs $x$", Format::Plain));
+    assert!(!can_write_back("a.qz#1\u{1f}one<br>---<br>two", Format::Plain));
+    assert!(!can_write_back("a.qz#1\u{1f}one\n###\ntwo", Format::Html));
+    assert!(!can_write_back("a.qz#1\u{1f}text", Format::Markdown));
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashSet, path::{Path, PathBuf}};
+
+use anki::{collection::CollectionBuilder, notes::NoteId, timestamp::TimestampSecs};
+use rusqlite::params;
+
+use crate::{Error, Result, get_config, find_files, load_cards, cards::{Deck, split_id}};
+
+pub const ORPHAN_TAG: &str = "anc::orphan";
+
+pub enum PruneAction {
+    Delete,
+    Tag,
+}
+
+/// A note that was created by anc but whose card no longer exists in any file
+pub struct Orphan {
+    pub note_id: i64,
+    pub id: String,
+    pub model: String,
+}
+
+/// Finds orphans and, if `confirm` agrees, deletes or tags them
+pub fn prune<F>(action: PruneAction, confirm: F) -> Result<Vec<String>>
+where F: FnOnce(&[Orphan]) -> bool
+{
+    let config = get_config()?;
+
+    let files = find_files(&config);
+    let decks = load_cards(&config, files.clone())?;
+
+    let path = config.anki_dir.join("collection.anki2");
+    let base_dir = config.config_dir.parent().unwrap();
+    let orphans = find_orphans(path.clone(), base_dir, &files, &decks)?;
+    if orphans.is_empty() {
+        return Ok(vec!["No orphaned notes".to_string()]);
+    }
+    if !confirm(&orphans) {
+        return Ok(vec!["Nothing was pruned".to_string()]);
+    }
+
+    let count = prune_orphans(path, &orphans, &action)?;
+    Ok(vec![match action {
+        PruneAction::Delete => format!("{} deleted", count),
+        PruneAction::Tag => format!("{} tagged {}", count, ORPHAN_TAG),
+    }])
+}
+
+/// Notes of the managed notetypes with an id generated from a file under `base_dir` that no card has anymore
+///
+/// That's the case once the file was deleted, or if it is one of the `parsed` files, which `decks` came from, and
+/// doesn't have the card anymore. Notes of files that still exist but weren't parsed, like newly ignored ones, are kept.
+/// Managed notetypes are the ones used by any parsed card as well as any notetype with an "Id" first field.
+pub fn find_orphans(path: PathBuf, base_dir: &Path, parsed: &[PathBuf], decks: &[Deck]) -> Result<Vec<Orphan>> {
+    let collection = CollectionBuilder::new(path).build()?;
+    let db = &collection.storage.db;
+
+    let ids: HashSet<&str> = decks.iter()
+        .flat_map(|d| d.groups.iter())
+        .flat_map(|g| g.cards.iter())
+        .map(|c| c.fields[0].as_str())
+        .collect();
+    let parsed: HashSet<&Path> = parsed.iter().map(|p| p.as_path()).collect();
+    let models: HashSet<String> = decks.iter()
+        .flat_map(|d| d.groups.iter())
+        .map(|g| g.model.to_lowercase())
+        .collect();
+
+    let mut notes = db.prepare(
+        "
+            SELECT n.id, SUBSTR(n.flds, 0, INSTR(n.flds, char(31))), nt.name, fd.name
+            FROM notes n
+            join notetypes nt on nt.id = n.mid
+            join fields fd on fd.ntid = nt.id and fd.ord = 0
+        ")?;
+    let orphans = notes.query_map([], |row| Ok((
+            row.get::<usize, i64>(0)?,
+            row.get::<usize, String>(1)?,
+            row.get::<usize, String>(2)?,
+            row.get::<usize, String>(3)?,
+        )))?
+        .filter_map(|r| r.ok())
+        .filter(|(_, id, model, first_field)| {
+            let managed = models.contains(&model.to_lowercase()) || first_field.eq_ignore_ascii_case("id");
+            let (file, index) = split_id(id);
+            let file = Path::new(file);
+            // compared by component, so a sibling like notes-es/ isn't part of notes/
+            managed && index.is_some() && file.starts_with(base_dir)
+                && (parsed.contains(file) || !file.exists())
+                && !ids.contains(id.as_str())
+        })
+        .map(|(note_id, id, model, _)| Orphan {
+            note_id,
+            id,
+            model,
+        })
+        .collect();
+    Ok(orphans)
+}
+
+pub fn prune_orphans(path: PathBuf, orphans: &[Orphan], action: &PruneAction) -> Result<usize> {
+    let mut collection = CollectionBuilder::new(path).build()?;
+    let note_ids: Vec<NoteId> = orphans.iter()
+        .map(|o| NoteId::from(o.note_id))
+        .collect();
+
+    match action {
+        PruneAction::Delete => {
+            collection.remove_notes(&note_ids)
+                .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+            Ok(note_ids.len())
+        },
+        PruneAction::Tag => {
+            collection.storage.db.prepare("savepoint anc")?.execute([])?;
+            let mut tag_note = collection.storage.db.prepare(
+                "update notes set mod = ?, usn = ?, tags = ' ' || ltrim(trim(tags) || ' ' || ?) || ' '
+                 where id = ? and tags not like ?"
+            )?;
+            let mut count = 0;
+            for o in orphans {
+                count += tag_note.execute(params![
+                    TimestampSecs::now(),
+                    -1,
+                    ORPHAN_TAG,
+                    o.note_id,
+                    format!("% {} %", ORPHAN_TAG),
+                ])?;
+            }
+            drop(tag_note);
+            // registers the new tag
+            collection.after_note_updates(&note_ids, false, false)
+                .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+            collection.storage.db.prepare("release anc")?.execute([])?; // commit
+            Ok(count)
+        },
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashSet, env, fs, path::{Path, PathBuf}};
+
+use anki::{collection::CollectionBuilder, notes::NoteId, timestamp::TimestampSecs};
+use itertools::Itertools;
+use rusqlite::params;
+
+use crate::{Error, Result, get_config, native_deck_name, cards::split_id, parsing::{Frontmatter, separator}};
+
+struct PulledNote {
+    note_id: i64,
+    fields: Vec<String>,
+    tags: String,
+    model: String,
+}
+
+/// Writes the notes of a deck that aren't managed by anc yet into .qz files in the current directory
+///
+/// Each note gets the id `parse` will generate for it written into its first field, so that the next save updates
+/// them instead of adding duplicates.
+pub fn pull(deck: &str, notetype: Option<&str>) -> Result<Vec<String>> {
+    let config = get_config()?;
+    let base_dir = config.config_dir.parent().unwrap().to_path_buf();
+    let out_dir = env::current_dir()
+        .and_then(|d| d.canonicalize())
+        .map_err(|e| Error::io(".", e))?;
+
+    let mut collection = CollectionBuilder::new(config.anki_dir.join("collection.anki2")).build()?;
+    collection.storage.db.prepare("savepoint anc")?.execute([])?;
+
+    let mut output = vec![];
+    let mut note_ids = vec![];
+    {
+        let db = &collection.storage.db;
+        let mut note_query = db.prepare(
+            "
+                SELECT n.id, n.flds, n.tags, nt.name, fd.name
+                FROM notes n
+                join cards c on c.nid = n.id
+                join decks d on d.id = c.did
+                join notetypes nt on nt.id = n.mid
+                join fields fd on fd.ntid = nt.id and fd.ord = 0
+                WHERE d.name like ? and (? is null or nt.name like ?)
+                GROUP BY n.id
+                ORDER BY n.id
+            ")?;
+        let mut set_id = db.prepare(
+            "update notes set mod = ?, usn = ?, flds = ?, sfld = ?
+             where id = ?"
+        )?;
+
+        let native_deck = native_deck_name(deck);
+        let mut unmanaged_types = HashSet::new();
+        let mut other_projects = 0;
+        let notes: Vec<PulledNote> = note_query.query_map(params![native_deck, notetype, notetype], |row| Ok((
+                row.get::<usize, i64>(0)?,
+                row.get::<usize, String>(1)?,
+                row.get::<usize, String>(2)?,
+                row.get::<usize, String>(3)?,
+                row.get::<usize, String>(4)?,
+            )))?
+            .filter_map(|r| r.ok())
+            .filter_map(|(note_id, flds, tags, model, first_field)| {
+                if !first_field.eq_ignore_ascii_case("id") {
+                    unmanaged_types.insert(model);
+                    return None;
+                }
+                let fields: Vec<String> = flds.split('\u{1f}').map(|f| f.to_string()).collect();
+                // already saved from a file, either of this project or of another one
+                let (file, index) = split_id(&fields[0]);
+                if index.is_some() && Path::new(file).is_absolute() {
+                    if !Path::new(file).starts_with(&base_dir) {
+                        other_projects += 1;
+                    }
+                    return None;
+                }
+                Some(PulledNote { note_id, fields, tags, model })
+            })
+            .collect();
+        let unwritable: Vec<Error> = notes.iter()
+            .filter(|n| n.fields.iter().skip(1).any(|f| f.lines().any(|l| separator(l).is_some() || l.trim() == "###")))
+            .map(|n| Error::Collection(format!("Note {} has a field containing a --- or ### line and can't be written as a .qz file", n.note_id)))
+            .collect();
+        if !unwritable.is_empty() {
+            return Err(Error::from_many(unwritable));
+        }
+        if other_projects > 0 {
+            output.push(format!("Skipped {} notes saved from files outside of {}", other_projects, base_dir.display()));
+        }
+        for model in unmanaged_types {
+            output.push(format!("Skipped notes of {}, its first field isn't an Id field", model));
+        }
+
+        let groups = notes.into_iter().into_group_map_by(|n| n.model.clone());
+        let single_type = groups.len() == 1;
+        for (model, notes) in groups.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+            let name = if single_type { slug(deck) } else { format!("{}-{}", slug(deck), slug(&model)) };
+            let path = free_path(&out_dir, &name);
+            let path_id = path.display().to_string();
+
+            let mut blocks = vec![];
+            for (i, mut n) in notes.into_iter().enumerate() {
+                let id = format!("{}#{}", path_id, i + 1);
+                n.fields[0] = id.clone();
+                let flds = n.fields.join("\u{1f}");
+                set_id.execute(params![
+                    TimestampSecs::now(),
+                    -1,
+                    flds,
+                    id,
+                    n.note_id,
+                ])?;
+                note_ids.push(NoteId::from(n.note_id));
+
+                let tags = n.tags.trim();
+                let frontmatter = Frontmatter::new(
+                    deck.to_string(),
+                    model.clone(),
+                    if tags.is_empty() { None } else { Some(tags.to_string()) },
+                    Some(true),
+                );
+                // trailing empty fields are padded again when saving
+                let fields: Vec<&String> = n.fields.iter()
+                    .skip(1)
+                    .rev()
+                    .skip_while(|f| f.is_empty())
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .collect();
+                let mut block = format!("---\n{}---\n", frontmatter.to_yaml());
+                block += &fields.iter().map(|f| format!("{}\n", f)).join("---\n");
+                blocks.push(block);
+            }
+
+            fs::write(&path, blocks.join("###\n")).map_err(|e| Error::io(&path, e))?;
+            output.push(format!("Pulled {} notes of {} into {}", blocks.len(), model, path.display()));
+        }
+    }
+
+    collection.after_note_updates(&note_ids, false, true)
+        .map_err(|e| Error::Collection(e.localized_description(&collection.tr)))?;
+    collection.storage.db.prepare("release anc")?.execute([])?; // commit
+
+    if output.is_empty() {
+        output.push(format!("No unmanaged notes in {}", deck));
+    }
+    Ok(output)
+}
+
+fn slug(name: &str) -> String {
+    name.split("::")
+        .map(|part| part.trim()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect::<String>())
+        .join("_")
+}
+
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(format!("{}.qz", name));
+    let mut index = 0;
+    while path.exists() {
+        index += 1;
+        path = dir.join(format!("{}{}.qz", name, index));
+    }
+    path
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use itertools::Itertools;
+
+use crate::{Error, Result, PATH_TAG_PREFIX, cards::split_id, prune::ORPHAN_TAG, parsing::{replace_fields, Format}};
+
+/// What a note looked like right after anc last saved it
+#[derive(Serialize, Deserialize)]
+pub struct NoteState {
+    pub hash: String, // of flds
+    pub modified: i64,
+    /// The tags that came from the file, as opposed to ones added in Anki, if known
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+/// Last saved state of every note, kept in `.anc/state` to tell apart file edits from edits made in Anki
+#[derive(Serialize, Deserialize, Default)]
+pub struct SyncState {
+    notes: HashMap<String, NoteState>,
+}
+
+impl SyncState {
+    pub fn load(config_dir: &Path) -> Result<SyncState> {
+        match fs::read_to_string(state_path(config_dir)) {
+            Err(_) => Ok(SyncState::default()),
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| Error::Config(format!("Could not read .anc/state: {}", e))),
+        }
+    }
+
+    pub fn save(&self, config_dir: &Path) -> Result<()> {
+        let path = state_path(config_dir);
+        fs::write(&path, serde_json::to_vec(self).unwrap())
+            .map_err(|e| Error::io(path, e))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&NoteState> {
+        self.notes.get(id)
+    }
+
+    pub fn record(&mut self, id: String, flds: &str, modified: i64, tags: Option<Vec<String>>) {
+        self.notes.insert(id, NoteState {
+            hash: hash(flds),
+            modified,
+            tags,
+        });
+    }
+
+    /// The tags anc set on the note at the last save, unless it wasn't recorded
+    pub fn owned_tags(&self, id: &str) -> Option<&[String]> {
+        self.notes.get(id).and_then(|n| n.tags.as_deref())
+    }
+}
+
+/// Which side wins for a note that is both in a file and in Anki
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    /// Save the file's version, which may be the same as Anki's
+    FromFile,
+    /// Only Anki's version changed, so it goes into the file
+    WriteBack,
+    Conflict,
+}
+
+/// Compares both versions of a note with the state of the last save
+///
+/// Notes that are the same on both sides are never conflicts, which also resolves earlier ones. `writable` is false for
+/// notes whose file can't take Anki's version, so those edits are conflicts, and `force` makes the file win anyway.
+pub fn resolve(saved: Option<&NoteState>, file_flds: &str, anki_flds: &str, anki_modified: i64, writable: bool, force: bool) -> Resolution {
+    let saved = match saved {
+        Some(saved) if file_flds != anki_flds => saved,
+        _ => return Resolution::FromFile,
+    };
+    let file_changed = saved.hash != hash(file_flds);
+    let anki_changed = saved.modified != anki_modified && saved.hash != hash(anki_flds);
+    if !anki_changed || force {
+        Resolution::FromFile
+    } else if file_changed || !writable {
+        Resolution::Conflict
+    } else {
+        Resolution::WriteBack
+    }
+}
+
+fn state_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("state")
+}
+
+/// The tags of a note after saving: the ones from its file followed by the ones added in Anki
+///
+/// Tags in `owned`, which anc set before, `anc::orphan` and, with `path_tags`, path tags belong to anc, so they are
+/// dropped once the file doesn't have them anymore. Without `owned`, like for notes saved before anc recorded it, the
+/// other tags are all kept. Tags are compared case-insensitively, like Anki does.
+pub fn merge_tags(existing: &str, owned: Option<&[String]>, file: &[String], path_tags: bool) -> Vec<String> {
+    let owned: Vec<String> = owned.unwrap_or_default().iter().map(|t| t.to_lowercase()).collect();
+    let path_prefix = format!("{}::", PATH_TAG_PREFIX).to_lowercase();
+    let from_file: Vec<String> = file.iter().map(|t| t.to_lowercase()).collect();
+    let added_in_anki = existing.split_whitespace()
+        .filter(|t| {
+            let t = t.to_lowercase();
+            !owned.contains(&t)
+                && t != ORPHAN_TAG
+                && (!path_tags || !t.starts_with(&path_prefix))
+                && !from_file.contains(&t)
+        })
+        .map(|t| t.to_string());
+    file.iter().cloned()
+        .chain(added_in_anki)
+        .unique_by(|t| t.to_lowercase())
+        .collect()
+}
+
+/// Whether the tags stored in Anki are `tags`, in any order
+pub fn same_tags(existing: &str, tags: &[String]) -> bool {
+    let existing: Vec<String> = existing.split_whitespace().map(|t| t.to_lowercase()).sorted().collect();
+    let tags: Vec<String> = tags.iter().map(|t| t.to_lowercase()).sorted().collect();
+    existing == tags
+}
+
+pub fn hash(flds: &str) -> String {
+    sha1::Sha1::from(flds).digest().to_string()
+}
+
+/// Replaces the fields of the note in its .qz file with the ones stored in Anki
+///
+/// Only notes with a generated id (`path#n`) can be found again.
+pub fn write_back(id: &str, flds: &str, format: Format) -> Result<()> {
+    let (file, index) = split_id(id);
+    let index: usize = index
+        .and_then(|i| i.parse().ok())
+        .ok_or_else(|| Error::parse(Some(id.to_string()), "no generated id, so it can't be written back"))?;
+    let text = fs::read_to_string(file)
+        .map_err(|e| Error::io(file, e))?;
+
+    let mut fields: Vec<String> = flds.split('\u{1f}')
+        .skip(1)
+        .map(|f| f.to_string())
+        .collect();
+    while fields.last().map(|f| f.is_empty()) == Some(true) {
+        fields.pop();
+    }
+
+    let replaced = replace_fields(&text, index - 1, &fields, format)
+        .map_err(|e| Error::parse(Some(file.to_string()), e.to_string()))?;
+    fs::write(file, replaced)
+        .map_err(|e| Error::io(file, e))
+}
+
+#[test]
+fn tags() {
+    let owned = vec!["old".to_string(), "anc::path::moved".to_string()];
+    let file = vec!["new".to_string(), "anc::path::here".to_string()];
+
+    let merged = merge_tags(" old leech anc::path::moved anc::path::gone anc::orphan anc::other Marked NEW ", Some(&owned), &file, true);
+    assert_eq!(merged, vec!["new", "anc::path::here", "leech", "anc::other", "Marked"]);
+    assert!(same_tags(" Marked leech anc::other new anc::path::here ", &merged));
+    assert_eq!(merge_tags(" leech ", Some(&[]), &[], true), vec!["leech"]);
+
+    // added in anki, since path tags are off
+    let merged = merge_tags(" old anc::path::gone ", Some(&owned), &file, false);
+    assert_eq!(merged, vec!["new", "anc::path::here", "anc::path::gone"]);
+    // nothing recorded yet, so only anc's own tags can go
+    let merged = merge_tags(" old leech anc::path::gone anc::orphan ", None, &file, true);
+    assert_eq!(merged, vec!["new", "anc::path::here", "old", "leech"]);
+    let merged = merge_tags(" old anc::path::gone ", None, &file, false);
+    assert_eq!(merged, vec!["new", "anc::path::here", "old", "anc::path::gone"]);
+}
+
+#[test]
+fn conflicts() {
+    let saved = NoteState { hash: hash("id\u{1f}old"), modified: 1, tags: None };
+
+    assert_eq!(resolve(None, "id\u{1f}file", "id\u{1f}anki", 2, true, false), Resolution::FromFile);
+    assert_eq!(resolve(Some(&saved), "id\u{1f}file", "id\u{1f}old", 1, true, false), Resolution::FromFile);
+    assert_eq!(resolve(Some(&saved), "id\u{1f}old", "id\u{1f}anki", 2, true, false), Resolution::WriteBack);
+    assert_eq!(resolve(Some(&saved), "id\u{1f}old", "id\u{1f}anki", 2, false, false), Resolution::Conflict);
+    assert_eq!(resolve(Some(&saved), "id\u{1f}file", "id\u{1f}anki", 2, true, false), Resolution::Conflict);
+    assert_eq!(resolve(Some(&saved), "id\u{1f}file", "id\u{1f}anki", 2, true, true), Resolution::FromFile);
+    // the file was edited to match anki
+    assert_eq!(resolve(Some(&saved), "id\u{1f}anki", "id\u{1f}anki", 2, true, false), Resolution::FromFile);
+}
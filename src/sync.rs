@@ -1,32 +1,60 @@
-use std::{path::PathBuf, io::Cursor};
+use std::{path::Path, io::Cursor};
 
 use anki::{sync::SyncAuth, collection::CollectionBuilder};
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use serde_pickle::{Deserializer, DeOptions, Value, HashableValue};
 
-use crate::get_config;
+use crate::{Error, Result, get_config};
 
-pub async fn sync() -> Result<Vec<String>, Vec<String>> {
-    let config = get_config().map_err(|e| vec![e.to_string()])?;
-    let auth = get_auth(&config.anki_dir)
+pub async fn sync() -> Result<Vec<String>> {
+    let config = get_config()?;
+    let auth = get_auth(&config.anki_dir, &config.profile)?
         .map(|(hkey, host_number)| {
             SyncAuth {
                 hkey,
                 host_number: host_number.unwrap_or(0) as u32,
             }
-        }).unwrap();
-    let mut collection = CollectionBuilder::new(config.anki_dir.join("collection.anki2")).build().unwrap();
-    collection.normal_sync(auth, |_progress, _done| { }).await
-        .map_err(|e| vec![e.to_string()])
-        .map(|o| vec![o.server_message])
+        })
+        .ok_or_else(|| Error::Sync(format!("Profile {} has no sync key, log in to AnkiWeb from Anki first", config.profile)))?;
+    config.hooks.pre_sync()?;
+    let mut collection = CollectionBuilder::new(config.anki_dir.join("collection.anki2")).build()?;
+    let output = collection.normal_sync(auth, |_progress, _done| { }).await
+        .map_err(|e| Error::Sync(e.to_string()))?;
+    config.hooks.post_sync()?;
+    Ok(vec![output.server_message])
 }
 
-pub fn get_auth(path: &PathBuf) -> Option<(String, Option<i64>)> {
-    let conn = rusqlite::Connection::open(path.parent().unwrap().join("prefs21.db")).unwrap();
-    let mut get_profile = conn.prepare("select cast(data as blob) from profiles where name = ?").unwrap();
-    let profile_bytes: Vec<u8> = get_profile.query(params!["Test"]).unwrap().next().unwrap().unwrap().get(0).unwrap();
-    let profile = Deserializer::new(Cursor::new(profile_bytes.as_slice()), DeOptions::new()).deserialize_value().unwrap();
-    // TODO: error that user needs to sync or login first
+/// The names of the Anki profiles next to the one in `anki_dir`
+pub fn profiles(anki_dir: &Path) -> Result<Vec<String>> {
+    let conn = open_prefs(anki_dir)?;
+    let mut get_names = conn.prepare("select name from profiles where name != '_global' order by name")?;
+    let names = get_names.query_map([], |row| row.get(0))?
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(names)
+}
+
+fn open_prefs(anki_dir: &Path) -> Result<rusqlite::Connection> {
+    let path = anki_dir.parent().unwrap().join("prefs21.db");
+    if !path.is_file() {
+        return Err(Error::Sync(format!("{} doesn't exist, is anki_dir an Anki profile folder?", path.display())));
+    }
+    Ok(rusqlite::Connection::open(path)?)
+}
+
+/// The sync key and host number stored in `profile`
+pub fn get_auth(anki_dir: &Path, profile: &str) -> Result<Option<(String, Option<i64>)>> {
+    let conn = open_prefs(anki_dir)?;
+    let mut get_profile = conn.prepare("select cast(data as blob) from profiles where name = ?")?;
+    let profile_bytes: Vec<u8> = match get_profile.query_row(params![profile], |row| row.get(0)).optional()? {
+        Some(bytes) => bytes,
+        None => return Err(Error::Sync(format!(
+            "No Anki profile named {}, set profile in .anc/config to one of: {}",
+            profile,
+            profiles(anki_dir)?.join(", "),
+        ))),
+    };
+    let profile = Deserializer::new(Cursor::new(profile_bytes.as_slice()), DeOptions::new()).deserialize_value()
+        .map_err(|e| Error::Sync(format!("Could not read the profile: {}", e)))?;
     if let Value::Dict(mut v) = profile {
         let hkey = v.remove(&HashableValue::String("syncKey".to_string()))
             .map(|k| {
@@ -44,6 +72,23 @@ pub fn get_auth(path: &PathBuf) -> Option<(String, Option<i64>)> {
                     None
                 }
             }).flatten();
-        hkey.map(|h| (h, host_number))
-    } else { None }
+        Ok(hkey.map(|h| (h, host_number)))
+    } else { Ok(None) }
+}
+
+#[test]
+fn missing_profile() {
+    let base = std::env::temp_dir().join(format!("anc-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(base.join("User 1")).unwrap();
+    let conn = rusqlite::Connection::open(base.join("prefs21.db")).unwrap();
+    conn.execute_batch("
+        create table if not exists profiles (name text primary key, data blob not null);
+        insert or replace into profiles values ('_global', x''), ('User 1', x''), ('Work', x'');
+    ").unwrap();
+
+    let anki_dir = base.join("User 1");
+    assert_eq!(profiles(&anki_dir).unwrap(), vec!["User 1", "Work"]);
+    let error = get_auth(&anki_dir, "Test").err().unwrap();
+    assert_eq!(error.to_string(), "No Anki profile named Test, set profile in .anc/config to one of: User 1, Work");
+    std::fs::remove_dir_all(base).unwrap();
 }
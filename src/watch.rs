@@ -0,0 +1,111 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::mpsc::{channel, Receiver}, time::Duration};
+
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::{Error, Result, get_config, find_files, load_cards, save_cards, AllConfiguration, SaveOptions, QZ_EXTENSION, cards::DeckReport, state::SyncState, cache::{FileCache, Settings}, defaults::DEFAULTS_FILE};
+
+/// How long files have to stay untouched before saving, so that editors writing in several steps only cause one save
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Saves the files that changed since the last save, then keeps saving the ones that change until the process is stopped
+pub fn watch<F>(create_decks: bool, mut on_save: F) -> Result<()>
+where F: FnMut(Result<Vec<DeckReport>>)
+{
+    let config = get_config()?;
+    let options = SaveOptions {
+        dry_run: false,
+        create_decks: create_decks || config.create_decks,
+        force: false,
+        path_tags: config.path_tags,
+    };
+    let (sender, events) = channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    watcher.watch(config.config_dir.parent().unwrap(), RecursiveMode::Recursive).map_err(watch_error)?;
+
+    let mut files: HashSet<PathBuf> = find_files(&config).into_iter().collect();
+    let mut changed = unsaved(&config, files.iter().cloned().collect())?;
+    loop {
+        if !changed.is_empty() {
+            on_save(save_files(&config, changed, options));
+        }
+        changed = next_changes(&config, &events, &mut files)?;
+    }
+}
+
+/// Waits for files to change until none did for `DEBOUNCE`, then returns the ones that need saving
+///
+/// Only new files and changed settings need the files to be found again, which would take long in large directories.
+fn next_changes(config: &AllConfiguration, events: &Receiver<notify::Result<Event>>, files: &mut HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut touched = HashSet::new();
+    loop {
+        let event = match touched.is_empty() {
+            true => events.recv().map_err(|_| Error::Config("Stopped receiving file changes".to_string()))?,
+            false => match events.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+        };
+        let event = event.map_err(watch_error)?;
+        // reading files, like saving does, isn't a change
+        if !event.kind.is_access() {
+            touched.extend(event.paths);
+        }
+    }
+
+    let settings_changed = touched.iter().any(|p| is_setting(config, p));
+    let mut candidates = vec![];
+    let mut new_files = false;
+    for path in touched {
+        match path.canonicalize() {
+            Ok(path) if path.is_file() => {
+                new_files |= !files.contains(&path)
+                    && (path.extension().is_some_and(|e| e == QZ_EXTENSION) || config.hooks.converts(&path));
+                candidates.push(path);
+            },
+            Ok(_) => {},
+            Err(_) => { files.remove(&path); },
+        }
+    }
+    if new_files || settings_changed {
+        *files = find_files(config).into_iter().collect();
+    }
+
+    let to_check = match settings_changed {
+        true => files.iter().cloned().collect(),
+        false => candidates.into_iter().filter(|p| files.contains(p)).collect(),
+    };
+    unsaved(config, to_check)
+}
+
+/// Whether `path` is a file that changes how other files are saved, see `Settings`
+fn is_setting(config: &AllConfiguration, path: &Path) -> bool {
+    let config_dir = &config.config_dir;
+    path == config_dir.join("config")
+        || path == config_dir.join("defaults")
+        || path.parent() == Some(&config_dir.join("hooks"))
+        || path.file_name().is_some_and(|n| n == DEFAULTS_FILE)
+}
+
+/// Of `paths`, the ones that changed since they were last saved, in order
+fn unsaved(config: &AllConfiguration, mut paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    paths.sort();
+    let cache = FileCache::load(&config.config_dir)?;
+    Ok(cache.changed(&paths, &mut Settings::new(&config.config_dir, &config.hooks)))
+}
+
+fn watch_error(error: notify::Error) -> Error {
+    Error::Config(format!("Could not watch files: {}", error))
+}
+
+fn save_files(config: &AllConfiguration, paths: Vec<PathBuf>, options: SaveOptions) -> Result<Vec<DeckReport>> {
+    let mut state = SyncState::load(&config.config_dir)?;
+    let mut cache = FileCache::load(&config.config_dir)?;
+    let cards = load_cards(config, paths.clone())?;
+    config.hooks.pre_save(&cards)?;
+    let reports = save_cards(config.anki_dir.join("collection.anki2"), cards, options, Some(&mut state))?;
+    state.save(&config.config_dir)?;
+    cache.record(&paths, &reports, &mut Settings::new(&config.config_dir, &config.hooks));
+    cache.save(&config.config_dir)?;
+    config.hooks.post_save(&reports)?;
+    Ok(reports)
+}
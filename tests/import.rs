@@ -1,7 +1,7 @@
-use anc::{parsing::BatchReader, process_cards};
+use anc::{parsing::BatchReader, process_cards, save_cards, SaveOptions, cards::NoteChange, prune::find_orphans};
 use rusqlite::params;
 
-use std::{sync::Once, fs::{copy, remove_file}, panic, path::PathBuf};
+use std::{sync::Once, fs::{copy, remove_file}, panic, path::{Path, PathBuf}};
 
 use anki::{collection::CollectionBuilder, decks::NativeDeckName, notetype::{NoteField, NoteFieldConfig}};
 
@@ -78,9 +78,9 @@ macro_rules! import_test {(
 
 fn run_with_strings(card_defs: Vec<(String, &str)>, path: String) {
     let cards = match BatchReader::from_string(card_defs).parse() {
-        Err(errors) => {
-            for p in errors {
-                eprintln!("{}", p);
+        Err(error) => {
+            for e in error.errors() {
+                eprintln!("{}", e);
             }
             std::process::exit(65);
         },
@@ -171,3 +171,132 @@ fn one_bad() {
 
     assert_eq!(count_query.query(params!["example"]).unwrap().next().unwrap().unwrap().get::<usize, i32>(0).unwrap(), 0);
 }
+
+fn count(path: &str, query: &str) -> i32 {
+    let collection = CollectionBuilder::new(path).build().unwrap();
+    collection.storage.db.query_row(query, [], |r| r.get(0)).unwrap()
+}
+
+#[macro_rules_attribute(import_test)]
+fn dry_run() {
+    let card = "---\n\
+                deck: example::new\n\
+                type: basic\n\
+                ---\n\
+                Question\n\
+                ---\n\
+                Answer";
+    let notes = count("dry_run.anki2", "select count() from notes");
+    let decks = count("dry_run.anki2", "select count() from decks");
+
+    let cards = BatchReader::from_string(vec![("dry.qz".to_string(), card)]).parse().unwrap();
+    let options = SaveOptions { dry_run: true, create_decks: true, ..SaveOptions::default() };
+    let reports = save_cards(PathBuf::from("dry_run.anki2"), cards, options, None).unwrap();
+    assert!(reports[0].created);
+    assert_eq!(reports[0].count(NoteChange::Added), 1);
+
+    assert_eq!(count("dry_run.anki2", "select count() from notes"), notes);
+    assert_eq!(count("dry_run.anki2", "select count() from decks"), decks);
+}
+
+#[macro_rules_attribute(import_test)]
+fn nested_decks() {
+    let card = "---\n\
+                deck: parent::child::leaf\n\
+                type: basic\n\
+                ---\n\
+                Question\n\
+                ---\n\
+                Answer";
+
+    let cards = BatchReader::from_string(vec![("nested.qz".to_string(), card)]).parse().unwrap();
+    let options = SaveOptions { create_decks: true, ..SaveOptions::default() };
+    save_cards(PathBuf::from("nested_decks.anki2"), cards, options, None).unwrap();
+
+    let names: Vec<String> = {
+        let collection = CollectionBuilder::new("nested_decks.anki2").build().unwrap();
+        let mut query = collection.storage.db.prepare("select name from decks where name like 'parent%' order by name").unwrap();
+        let names = query.query_map([], |r| r.get(0)).unwrap()
+            .map(|n| n.unwrap())
+            .collect();
+        names
+    };
+    assert_eq!(names, vec!["parent", "parent\x1fchild", "parent\x1fchild\x1fleaf"]);
+    assert_eq!(count("nested_decks.anki2", "
+        select count()
+        from cards c
+        join decks d
+        on c.did = d.id
+        where d.name = 'parent\x1fchild\x1fleaf'
+    "), 1);
+}
+
+#[macro_rules_attribute(import_test)]
+fn prune_sibling_project() {
+    let card = "---\n\
+                deck: example\n\
+                type: basic\n\
+                ---\n\
+                Question\n\
+                ---\n\
+                Answer\n\
+                ###\n\
+                ---\n\
+                deck: example\n\
+                type: basic\n\
+                ---\n\
+                Second\n\
+                ---\n\
+                Answer";
+
+    run_with_strings(
+        vec![
+            ("/projects/notes/a.qz".to_string(), card),
+            ("/projects/notes-es/a.qz".to_string(), card),
+        ],
+        "prune_sibling_project.anki2".to_string()
+    );
+
+    // the second note of notes/a.qz was deleted from the file
+    let decks = BatchReader::from_string(vec![("/projects/notes/a.qz".to_string(), card.split("###").next().unwrap())])
+        .parse()
+        .unwrap();
+    let orphans = find_orphans(PathBuf::from("prune_sibling_project.anki2"), Path::new("/projects/notes"), &[], &decks).unwrap();
+
+    let ids: Vec<&str> = orphans.iter().map(|o| o.id.as_str()).collect();
+    assert_eq!(ids, vec!["/projects/notes/a.qz#2"]);
+}
+
+#[macro_rules_attribute(import_test)]
+fn prune_unparsed_files() {
+    let file = Path::new("test_files/good/basic.qz").canonicalize().unwrap();
+    let base_dir = file.parent().unwrap();
+    let card = "---\n\
+                deck: example\n\
+                type: basic\n\
+                ---\n\
+                Question\n\
+                ---\n\
+                Answer\n\
+                ###\n\
+                ---\n\
+                deck: example\n\
+                type: basic\n\
+                ---\n\
+                Second\n\
+                ---\n\
+                Answer";
+    let path = file.display().to_string();
+    run_with_strings(vec![(path.clone(), card)], "prune_unparsed_files.anki2".to_string());
+
+    // the file still exists, it's just ignored now
+    let orphans = find_orphans(PathBuf::from("prune_unparsed_files.anki2"), base_dir, &[], &[]).unwrap();
+    assert!(orphans.is_empty());
+
+    let decks = BatchReader::from_string(vec![(path.clone(), card.split("###").next().unwrap())])
+        .parse()
+        .unwrap();
+    let orphans = find_orphans(PathBuf::from("prune_unparsed_files.anki2"), base_dir, &[file.clone()], &decks).unwrap();
+    let ids: Vec<&str> = orphans.iter().map(|o| o.id.as_str()).collect();
+    assert_eq!(ids, vec![format!("{}#2", path)]);
+}